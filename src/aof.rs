@@ -0,0 +1,360 @@
+// Copyright 2023, Antoine Catton
+//
+// Permission to use, copy, modify, and/or distribute this software for any purpose with or without
+// fee is hereby granted, provided that the above copyright notice and this permission notice
+// appear in all copies.
+//
+// THE SOFTWARE IS PROVIDED “AS IS” AND THE AUTHOR DISCLAIMS ALL WARRANTIES WITH REGARD TO THIS
+// SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE
+// AUTHOR BE LIABLE FOR ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+// WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN ACTION OF CONTRACT,
+// NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF OR IN CONNECTION WITH THE USE OR PERFORMANCE
+// OF THIS SOFTWARE.
+
+//! Append-only log of all the modifications of the store.
+//!
+//! Each operation is prefixed by its length and its kind. A "set" contains the key and its value in
+//! the snapshot format, a "delete" and an "expire" only contain the key. A change of expiration is
+//! logged as a "set" of the whole entry, since the previous "set" may have expired by the time the
+//! log is replayed. A "clear" starts every log rewritten by the compaction, since the log then
+//! contains the whole store.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use tokio::task;
+use tokio::time::{self, Duration, Instant};
+
+use crate::snapshot::{self, Reader, Record};
+use crate::{Entry, State, Store};
+
+const SET: u8 = 1;
+const DELETE: u8 = 2;
+const EXPIRE: u8 = 3;
+const CLEAR: u8 = 4;
+
+/// The log is not compacted below this size.
+const MIN_COMPACTION_SIZE: u64 = 1024 * 1024;
+
+/// When to flush the log to the disk.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fsync {
+    /// After every operation, before answering the client.
+    Always,
+    /// Once per second, in the background.
+    EverySecond,
+    /// Let the operating system decide.
+    Never,
+}
+
+fn record(buf: &mut Vec<u8>, kind: u8, payload: &[u8]) {
+    buf.extend((payload.len() as u64 + 1).to_be_bytes());
+    buf.push(kind);
+    buf.extend(payload);
+}
+
+pub fn set_record(key: &str, entry: &Entry) -> Vec<u8> {
+    let mut payload = Vec::new();
+    snapshot::put_entry(&mut payload, key, entry);
+    let mut buf = Vec::new();
+    record(&mut buf, SET, &payload);
+    buf
+}
+
+/// A change of the expiration of an entry, logged before the entry is changed.
+pub fn expiration_record(
+    key: &str,
+    entry: &Entry,
+    deadline: Option<Instant>,
+    sliding: Option<Duration>,
+) -> Vec<u8> {
+    let mut payload = Vec::new();
+    snapshot::put_entry_expiring(&mut payload, key, entry, deadline, sliding);
+    let mut buf = Vec::new();
+    record(&mut buf, SET, &payload);
+    buf
+}
+
+pub fn delete_record(key: &str) -> Vec<u8> {
+    let mut buf = Vec::new();
+    record(&mut buf, DELETE, key.as_bytes());
+    buf
+}
+
+pub fn expire_record(key: &str) -> Vec<u8> {
+    let mut buf = Vec::new();
+    record(&mut buf, EXPIRE, key.as_bytes());
+    buf
+}
+
+#[derive(Debug)]
+struct LogFile {
+    file: File,
+    size: u64,
+    /// Size of the log right after the last compaction.
+    compacted_size: u64,
+}
+
+#[derive(Debug)]
+pub struct Aof {
+    path: PathBuf,
+    fsync: Fsync,
+    log: Mutex<LogFile>,
+}
+
+impl Aof {
+    pub fn open(path: PathBuf, fsync: Fsync) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .context("Could not open append-only log")?;
+        let size = file.metadata()?.len();
+        Ok(Aof {
+            path,
+            fsync,
+            log: Mutex::new(LogFile {
+                file,
+                size,
+                compacted_size: 0,
+            }),
+        })
+    }
+
+    fn write(&self, data: &[u8]) -> io::Result<()> {
+        let mut log = self.log.lock().expect("Append-only log lock poisoned");
+        log.file.write_all(data)?;
+        if self.fsync == Fsync::Always {
+            log.file.sync_data()?;
+        }
+        log.size += data.len() as u64;
+        Ok(())
+    }
+
+    /// Append an operation to the log. The caller must hold the write lock of the store, so that
+    /// the operations are logged in the same order as they are applied.
+    pub async fn append(self: &Arc<Self>, data: Vec<u8>) -> Result<()> {
+        let aof = self.clone();
+        task::spawn_blocking(move || aof.write(&data))
+            .await
+            .context("Append-only log writer panicked")?
+            .context("Could not write to append-only log")
+    }
+
+    /// Apply all the operations of the log to the store.
+    pub async fn replay(&self, state: &State) -> Result<()> {
+        let path = self.path.clone();
+        let data = task::spawn_blocking(move || fs::read(path))
+            .await
+            .context("Append-only log reader panicked")?
+            .context("Could not read append-only log")?;
+
+        let mut reader = Reader(&data);
+        let mut replayed = 0;
+        while !reader.0.is_empty() {
+            let op = match reader.bytes() {
+                Ok(op) if !op.is_empty() => op,
+                _ => {
+                    // The server most likely crashed while writing the last operation.
+                    log::warn!("Ignoring truncated operation at the end of the append-only log");
+                    break;
+                }
+            };
+            let (kind, mut payload) = (op[0], Reader(&op[1..]));
+            let mut write_kv = state.kv.write().await;
+            match kind {
+                SET => {
+                    let record = Record::decode(&mut payload).context("Invalid set operation")?;
+                    let key = record.key.clone();
+                    match record.into_entry(state) {
+                        Some((key, entry)) => state.insert(&mut write_kv, key, entry),
+                        None => {
+                            write_kv.remove(&key);
+                        }
+                    }
+                }
+                DELETE | EXPIRE => {
+                    let key = std::str::from_utf8(payload.0).context("Invalid key")?;
+                    write_kv.remove(key);
+                }
+                CLEAR => write_kv.clear(),
+                kind => anyhow::bail!("Unknown operation {kind} in append-only log"),
+            }
+            replayed += 1;
+        }
+        log::info!("Replayed {replayed} operations from append-only log");
+        Ok(())
+    }
+
+    fn rewrite(&self, data: &[u8]) -> io::Result<()> {
+        // The lock is held during the whole rewrite, so that nothing is written to the old file
+        // after it was replaced.
+        let mut log = self.log.lock().expect("Append-only log lock poisoned");
+        snapshot::write_atomically(&self.path, data)?;
+        log.file = OpenOptions::new().append(true).open(&self.path)?;
+        log.size = data.len() as u64;
+        log.compacted_size = log.size;
+        Ok(())
+    }
+
+    /// Rewrite the log from the content of the store. The caller must hold a lock on the store, so
+    /// that no operation happens during the compaction.
+    pub async fn compact(self: &Arc<Self>, kv: &Store) -> Result<()> {
+        let mut data = Vec::new();
+        record(&mut data, CLEAR, &[]);
+        for (key, entry) in kv.iter() {
+            data.extend(set_record(key, entry));
+        }
+        let aof = self.clone();
+        task::spawn_blocking(move || aof.rewrite(&data))
+            .await
+            .context("Append-only log writer panicked")?
+            .context("Could not rewrite append-only log")?;
+        log::debug!("Append-only log compacted");
+        Ok(())
+    }
+
+    fn sync_data(&self) -> io::Result<()> {
+        self.log
+            .lock()
+            .expect("Append-only log lock poisoned")
+            .file
+            .sync_data()
+    }
+
+    /// Flush the log to the disk.
+    pub async fn sync(self: &Arc<Self>) -> Result<()> {
+        let aof = self.clone();
+        task::spawn_blocking(move || aof.sync_data())
+            .await
+            .context("Append-only log writer panicked")?
+            .context("Could not sync append-only log")
+    }
+
+    fn needs_compaction(&self) -> bool {
+        let log = self.log.lock().expect("Append-only log lock poisoned");
+        log.size > MIN_COMPACTION_SIZE.max(2 * log.compacted_size)
+    }
+}
+
+/// Flush the log to the disk every second if needed, and compact it once it doubled in size.
+pub async fn background(state: State, aof: Arc<Aof>) {
+    let mut ticker = time::interval(Duration::from_secs(1));
+    loop {
+        ticker.tick().await;
+        if aof.fsync == Fsync::EverySecond {
+            if let Err(err) = aof.sync().await {
+                log::error!("{err:#}");
+            }
+        }
+        if aof.needs_compaction() {
+            if let Err(err) = aof.compact(&*state.kv.read().await).await {
+                log::error!("{err:#}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::tests::{entry, state};
+
+    /// Write the operations to a new log, and replay it in an empty store.
+    async fn replay(name: &str, ops: &[Vec<u8>]) -> State {
+        let path =
+            std::env::temp_dir().join(format!("memoryhttpd-{}-{name}.aof", std::process::id()));
+        fs::write(&path, ops.concat()).unwrap();
+        let aof = Aof::open(path.clone(), Fsync::Never).unwrap();
+        let state = state();
+        let result = aof.replay(&state).await;
+        fs::remove_file(&path).unwrap();
+        result.unwrap();
+        state
+    }
+
+    fn keys(kv: &Store) -> Vec<&str> {
+        kv.keys().map(String::as_str).collect()
+    }
+
+    #[tokio::test]
+    async fn set_delete_expire_clear() {
+        let state = state();
+        let deadline = Instant::now() + Duration::from_secs(60);
+        let a = entry(&state, b"a", None);
+        let b = entry(&state, b"b", None);
+        let c = entry(&state, b"c", None);
+        let d = entry(&state, b"d", Some(deadline));
+        let mut clear = Vec::new();
+        record(&mut clear, CLEAR, &[]);
+
+        let replayed = replay(
+            "set-delete-expire",
+            &[
+                set_record("example.com/a", &a),
+                set_record("example.com/b", &b),
+                set_record("example.com/c", &c),
+                set_record("example.com/d", &d),
+                delete_record("example.com/a"),
+                expire_record("example.com/b"),
+            ],
+        )
+        .await;
+        let kv = replayed.kv.read().await;
+        assert_eq!(keys(&kv), ["example.com/c", "example.com/d"]);
+        assert_eq!(kv["example.com/c"].value, b"c");
+        assert_eq!(kv["example.com/c"].expires(), None);
+        assert!(kv["example.com/d"].expires().is_some_and(|e| e <= deadline));
+
+        let replayed = replay(
+            "clear",
+            &[
+                set_record("example.com/a", &a),
+                clear,
+                set_record("example.com/b", &b),
+            ],
+        )
+        .await;
+        assert_eq!(keys(&*replayed.kv.read().await), ["example.com/b"]);
+    }
+
+    #[tokio::test]
+    async fn change_of_expiration() {
+        let state = state();
+        let a = entry(&state, b"a", None);
+        let deadline = Instant::now() + Duration::from_secs(60);
+        let replayed = replay(
+            "expiration",
+            &[
+                set_record("example.com/a", &a),
+                expiration_record("example.com/a", &a, Some(deadline), None),
+            ],
+        )
+        .await;
+        let kv = replayed.kv.read().await;
+        assert!(kv["example.com/a"].expires().is_some_and(|e| e <= deadline));
+    }
+
+    #[tokio::test]
+    async fn truncated_last_operation() {
+        let state = state();
+        let a = entry(&state, b"a", None);
+        let mut truncated = set_record("example.com/b", &entry(&state, b"b", None));
+        truncated.truncate(truncated.len() - 1);
+        let replayed = replay("truncated", &[set_record("example.com/a", &a), truncated]).await;
+        assert_eq!(keys(&*replayed.kv.read().await), ["example.com/a"]);
+
+        // Even the length of the operation can be cut.
+        let replayed = replay(
+            "truncated-length",
+            &[set_record("example.com/a", &a), vec![0, 0, 0]],
+        )
+        .await;
+        assert_eq!(keys(&*replayed.kv.read().await), ["example.com/a"]);
+    }
+}
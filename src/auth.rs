@@ -0,0 +1,308 @@
+// Copyright 2023, Antoine Catton
+//
+// Permission to use, copy, modify, and/or distribute this software for any purpose with or without
+// fee is hereby granted, provided that the above copyright notice and this permission notice
+// appear in all copies.
+//
+// THE SOFTWARE IS PROVIDED “AS IS” AND THE AUTHOR DISCLAIMS ALL WARRANTIES WITH REGARD TO THIS
+// SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE
+// AUTHOR BE LIABLE FOR ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+// WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN ACTION OF CONTRACT,
+// NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF OR IN CONNECTION WITH THE USE OR PERFORMANCE
+// OF THIS SOFTWARE.
+
+//! Authentication with bearer tokens.
+//!
+//! The tokens file contains one rule per line, with four columns separated by whitespace: the
+//! token, the hosts, the path prefixes and the methods it allows. Hosts, prefixes and methods are
+//! separated by commas, "*" allows any of them. Empty lines and lines starting with "#" are
+//! ignored. For example:
+//!
+//! ```text
+//! # token   hosts                     prefixes                      methods
+//! s3cr3t    example.com,example.net   /.well-known/acme-challenge/  PUT,DELETE
+//! admin     *                         /                             *
+//! ```
+
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use http::header::{self, HeaderMap};
+use hyper::Method;
+
+#[derive(Debug)]
+struct Rule {
+    token: String,
+    /// None allows all the hosts.
+    hosts: Option<Vec<String>>,
+    prefixes: Vec<String>,
+    /// None allows all the methods.
+    methods: Option<Vec<Method>>,
+}
+
+impl Rule {
+    fn allows(&self, method: &Method, host: &str, path: &str) -> bool {
+        let host = host_without_port(host);
+        self.hosts
+            .as_ref()
+            .map(|hosts| hosts.iter().any(|h| h.eq_ignore_ascii_case(host)))
+            .unwrap_or(true)
+            && self.prefixes.iter().any(|p| path.starts_with(p.as_str()))
+            && self
+                .methods
+                .as_ref()
+                .map(|methods| methods.contains(method))
+                .unwrap_or(true)
+    }
+}
+
+pub fn host_without_port(host: &str) -> &str {
+    match host.rsplit_once(':') {
+        Some((name, port)) if port.bytes().all(|b| b.is_ascii_digit()) => name,
+        _ => host,
+    }
+}
+
+/// Compare two tokens in constant time, so that the response time doesn't leak how much of a
+/// token was guessed right.
+pub fn tokens_match(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |acc, (x, y)| acc | (x ^ y))
+            == 0
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Decision {
+    Allowed,
+    /// No token, or an unknown token.
+    Unauthorized,
+    /// The token doesn't allow this request.
+    Forbidden,
+}
+
+#[derive(Debug)]
+pub struct Auth {
+    rules: Vec<Rule>,
+    /// Also require a token for GET, HEAD and OPTIONS.
+    protect_reads: bool,
+}
+
+impl Auth {
+    pub fn load(path: &Path, protect_reads: bool) -> Result<Self> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Could not read {path}", path = path.display()))?;
+        let mut rules = Vec::new();
+        for (number, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let rule = parse_rule(line).with_context(|| {
+                format!(
+                    "Invalid rule on {path}:{line}",
+                    path = path.display(),
+                    line = number + 1
+                )
+            })?;
+            rules.push(rule);
+        }
+        Ok(Auth {
+            rules,
+            protect_reads,
+        })
+    }
+
+    pub fn check(&self, headers: &HeaderMap, method: &Method, host: &str, path: &str) -> Decision {
+        let read = [Method::GET, Method::HEAD, Method::OPTIONS].contains(method);
+        if read && !self.protect_reads {
+            return Decision::Allowed;
+        }
+        self.authenticate(headers, method, host, path)
+    }
+
+    /// Check the token of a request, even if it's a read and reads aren't protected.
+    pub fn authenticate(
+        &self,
+        headers: &HeaderMap,
+        method: &Method,
+        host: &str,
+        path: &str,
+    ) -> Decision {
+        let token = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.split_once(' '))
+            // The authentication scheme is case-insensitive.
+            .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("Bearer"))
+            .map(|(_, token)| token.trim());
+        let token = match token {
+            Some(token) => token,
+            None => return Decision::Unauthorized,
+        };
+        let mut rules = self
+            .rules
+            .iter()
+            .filter(|r| tokens_match(&r.token, token))
+            .peekable();
+        if rules.peek().is_none() {
+            return Decision::Unauthorized;
+        }
+        if rules.any(|r| r.allows(method, host, path)) {
+            Decision::Allowed
+        } else {
+            Decision::Forbidden
+        }
+    }
+}
+
+fn parse_list(column: &str) -> Option<Vec<String>> {
+    (column != "*").then(|| column.split(',').map(str::to_owned).collect())
+}
+
+fn parse_rule(line: &str) -> Result<Rule> {
+    let columns: Vec<&str> = line.split_whitespace().collect();
+    let [token, hosts, prefixes, methods] = columns[..] else {
+        anyhow::bail!("Expected 4 columns, got {count}", count = columns.len());
+    };
+    let prefixes = match prefixes {
+        "*" => vec!["/".to_owned()],
+        prefixes => prefixes.split(',').map(str::to_owned).collect(),
+    };
+    let methods = parse_list(methods)
+        .map(|methods| {
+            methods
+                .iter()
+                .map(|m| Method::from_bytes(m.to_uppercase().as_bytes()))
+                .collect::<Result<Vec<_>, _>>()
+        })
+        .transpose()
+        .context("Invalid method")?;
+    Ok(Rule {
+        token: token.to_owned(),
+        hosts: parse_list(hosts),
+        prefixes,
+        methods,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn auth(rules: &[&str], protect_reads: bool) -> Auth {
+        Auth {
+            rules: rules.iter().map(|r| parse_rule(r).unwrap()).collect(),
+            protect_reads,
+        }
+    }
+
+    fn authorization(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, value.parse().unwrap());
+        headers
+    }
+
+    fn bearer(token: &str) -> HeaderMap {
+        authorization(&format!("Bearer {token}"))
+    }
+
+    #[test]
+    fn parse_rule_columns() {
+        let rule = parse_rule("s3cr3t example.com,example.net /a/,/b/ put,DELETE").unwrap();
+        assert_eq!(rule.token, "s3cr3t");
+        assert_eq!(
+            rule.hosts,
+            Some(vec!["example.com".to_owned(), "example.net".to_owned()])
+        );
+        assert_eq!(rule.prefixes, ["/a/", "/b/"]);
+        assert_eq!(rule.methods, Some(vec![Method::PUT, Method::DELETE]));
+    }
+
+    #[test]
+    fn parse_rule_wildcards() {
+        let rule = parse_rule("admin * * *").unwrap();
+        assert_eq!(rule.hosts, None);
+        assert_eq!(rule.prefixes, ["/"]);
+        assert_eq!(rule.methods, None);
+    }
+
+    #[test]
+    fn parse_rule_invalid() {
+        assert!(parse_rule("token example.com /").is_err());
+        assert!(parse_rule("token example.com / PUT extra").is_err());
+        assert!(parse_rule("token example.com / P(T").is_err());
+    }
+
+    #[test]
+    fn rule_scoping() {
+        let rule = parse_rule("t example.com /acme/ PUT").unwrap();
+        assert!(rule.allows(&Method::PUT, "example.com", "/acme/token"));
+        assert!(rule.allows(&Method::PUT, "EXAMPLE.com:8080", "/acme/token"));
+        assert!(!rule.allows(&Method::PUT, "example.net", "/acme/token"));
+        assert!(!rule.allows(&Method::PUT, "example.com", "/other"));
+        assert!(!rule.allows(&Method::DELETE, "example.com", "/acme/token"));
+    }
+
+    #[test]
+    fn check_decisions() {
+        let auth = auth(&["t example.com /acme/ PUT"], false);
+        let check = |headers: &HeaderMap, method: &Method, path: &str| {
+            auth.check(headers, method, "example.com", path)
+        };
+        assert_eq!(
+            check(&HeaderMap::new(), &Method::PUT, "/acme/x"),
+            Decision::Unauthorized
+        );
+        assert_eq!(
+            check(&bearer("unknown"), &Method::PUT, "/acme/x"),
+            Decision::Unauthorized
+        );
+        assert_eq!(
+            check(&bearer("t"), &Method::PUT, "/acme/x"),
+            Decision::Allowed
+        );
+        assert_eq!(
+            check(&bearer("t"), &Method::PUT, "/other"),
+            Decision::Forbidden
+        );
+        assert_eq!(
+            check(&bearer("t"), &Method::DELETE, "/acme/x"),
+            Decision::Forbidden
+        );
+    }
+
+    #[test]
+    fn reads() {
+        let public = auth(&["t * / GET"], false);
+        assert_eq!(
+            public.check(&HeaderMap::new(), &Method::GET, "example.com", "/"),
+            Decision::Allowed
+        );
+        assert_eq!(
+            public.authenticate(&HeaderMap::new(), &Method::GET, "example.com", "/"),
+            Decision::Unauthorized
+        );
+        let protected = auth(&["t * / GET"], true);
+        assert_eq!(
+            protected.check(&HeaderMap::new(), &Method::GET, "example.com", "/"),
+            Decision::Unauthorized
+        );
+        assert_eq!(
+            protected.check(&bearer("t"), &Method::GET, "example.com", "/"),
+            Decision::Allowed
+        );
+    }
+
+    #[test]
+    fn scheme() {
+        let auth = auth(&["t * / *"], false);
+        let check = |value: &str| auth.check(&authorization(value), &Method::PUT, "a", "/");
+        assert_eq!(check("bearer t"), Decision::Allowed);
+        assert_eq!(check("BEARER  t"), Decision::Allowed);
+        assert_eq!(check("Basic t"), Decision::Unauthorized);
+        assert_eq!(check("Bearert"), Decision::Unauthorized);
+    }
+}
@@ -0,0 +1,220 @@
+// Copyright 2023, Antoine Catton
+//
+// Permission to use, copy, modify, and/or distribute this software for any purpose with or without
+// fee is hereby granted, provided that the above copyright notice and this permission notice
+// appear in all copies.
+//
+// THE SOFTWARE IS PROVIDED “AS IS” AND THE AUTHOR DISCLAIMS ALL WARRANTIES WITH REGARD TO THIS
+// SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE
+// AUTHOR BE LIABLE FOR ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+// WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN ACTION OF CONTRACT,
+// NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF OR IN CONNECTION WITH THE USE OR PERFORMANCE
+// OF THIS SOFTWARE.
+
+//! Conditional requests, as described in RFC 9110 section 13.
+
+use std::time::SystemTime;
+
+use http::header::{self, HeaderMap, HeaderName};
+use httpdate::HttpDate;
+use sha2::{Digest, Sha256};
+
+/// Compute the strong entity tag of a value, including its surrounding quotes.
+pub fn etag(value: &[u8]) -> String {
+    let digest = Sha256::digest(value);
+    let hex: String = digest[..16].iter().map(|b| format!("{b:02x}")).collect();
+    format!("\"{hex}\"")
+}
+
+#[derive(Debug)]
+enum ETags {
+    Any,
+    List(Vec<String>),
+}
+
+impl ETags {
+    fn from_headers(headers: &HeaderMap, name: &HeaderName) -> Option<Self> {
+        let mut tags = Vec::new();
+        for value in headers.get_all(name) {
+            // A header which is not valid ASCII can't contain any valid entity tag.
+            let value = value.to_str().unwrap_or_default();
+            for tag in value.split(',').map(str::trim).filter(|t| !t.is_empty()) {
+                if tag == "*" {
+                    return Some(ETags::Any);
+                }
+                tags.push(tag.to_owned());
+            }
+        }
+        headers.contains_key(name).then_some(ETags::List(tags))
+    }
+
+    fn matches_strong(&self, etag: &str) -> bool {
+        match self {
+            ETags::Any => true,
+            ETags::List(tags) => tags.iter().any(|t| t == etag),
+        }
+    }
+
+    fn matches_weak(&self, etag: &str) -> bool {
+        let etag = etag.trim_start_matches("W/");
+        match self {
+            ETags::Any => true,
+            ETags::List(tags) => tags.iter().any(|t| t.trim_start_matches("W/") == etag),
+        }
+    }
+}
+
+/// Validators of an existing value.
+pub struct Validators<'a> {
+    pub etag: &'a str,
+    pub modified: SystemTime,
+}
+
+/// Preconditions sent by the client with a request.
+#[derive(Debug)]
+pub struct Preconditions {
+    if_match: Option<ETags>,
+    if_none_match: Option<ETags>,
+    if_modified_since: Option<HttpDate>,
+}
+
+impl Preconditions {
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        Preconditions {
+            if_match: ETags::from_headers(headers, &header::IF_MATCH),
+            if_none_match: ETags::from_headers(headers, &header::IF_NONE_MATCH),
+            // An invalid date must be ignored.
+            if_modified_since: headers
+                .get(header::IF_MODIFIED_SINCE)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse().ok()),
+        }
+    }
+
+    /// Whether a read of the current value can be answered with "304 Not Modified".
+    pub fn not_modified(&self, current: &Validators) -> bool {
+        match (&self.if_none_match, self.if_modified_since) {
+            (Some(tags), _) => tags.matches_weak(current.etag),
+            (None, Some(since)) => HttpDate::from(current.modified) <= since,
+            (None, None) => false,
+        }
+    }
+
+    /// Whether a modification of the current value (None if there is no value) can proceed, or
+    /// needs to be answered with "412 Precondition Failed".
+    pub fn allow_write(&self, current: Option<Validators>) -> bool {
+        let etag = current.as_ref().map(|v| v.etag);
+        if let Some(tags) = &self.if_match {
+            if !etag.map(|e| tags.matches_strong(e)).unwrap_or(false) {
+                return false;
+            }
+        }
+        if let Some(tags) = &self.if_none_match {
+            if etag.map(|e| tags.matches_weak(e)).unwrap_or(false) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    const ETAG: &str = "\"abc\"";
+
+    fn preconditions(headers: &[(HeaderName, &str)]) -> Preconditions {
+        let headers: HeaderMap = headers
+            .iter()
+            .map(|(name, value)| (name.clone(), value.parse().unwrap()))
+            .collect();
+        Preconditions::from_headers(&headers)
+    }
+
+    fn validators(modified: SystemTime) -> Validators<'static> {
+        Validators {
+            etag: ETAG,
+            modified,
+        }
+    }
+
+    #[test]
+    fn if_none_match_weak() {
+        let current = validators(SystemTime::now());
+        let weak = preconditions(&[(header::IF_NONE_MATCH, "\"x\", W/\"abc\"")]);
+        assert!(weak.not_modified(&current));
+        assert!(!weak.allow_write(Some(validators(SystemTime::now()))));
+        let other = preconditions(&[(header::IF_NONE_MATCH, "W/\"x\"")]);
+        assert!(!other.not_modified(&current));
+        assert!(other.allow_write(Some(validators(SystemTime::now()))));
+    }
+
+    #[test]
+    fn if_none_match_any() {
+        let any = preconditions(&[(header::IF_NONE_MATCH, "*")]);
+        assert!(any.not_modified(&validators(SystemTime::now())));
+    }
+
+    #[test]
+    fn if_none_match_before_if_modified_since() {
+        let modified = SystemTime::now() - Duration::from_secs(3600);
+        let since = httpdate::fmt_http_date(SystemTime::now());
+        let preconditions = preconditions(&[
+            (header::IF_NONE_MATCH, "\"x\""),
+            (header::IF_MODIFIED_SINCE, &since),
+        ]);
+        assert!(!preconditions.not_modified(&validators(modified)));
+    }
+
+    #[test]
+    fn if_modified_since() {
+        let modified = SystemTime::now() - Duration::from_secs(3600);
+        let since = httpdate::fmt_http_date(SystemTime::now());
+        let after = preconditions(&[(header::IF_MODIFIED_SINCE, &since)]);
+        assert!(after.not_modified(&validators(modified)));
+        let before = preconditions(&[(header::IF_MODIFIED_SINCE, &since)]);
+        let modified = SystemTime::now() + Duration::from_secs(3600);
+        assert!(!before.not_modified(&validators(modified)));
+    }
+
+    #[test]
+    fn invalid_if_modified_since_ignored() {
+        let modified = SystemTime::now() - Duration::from_secs(3600);
+        let invalid = preconditions(&[(header::IF_MODIFIED_SINCE, "yesterday")]);
+        assert!(!invalid.not_modified(&validators(modified)));
+    }
+
+    #[test]
+    fn if_match() {
+        let current = || Some(validators(SystemTime::now()));
+        assert!(preconditions(&[(header::IF_MATCH, ETAG)]).allow_write(current()));
+        assert!(preconditions(&[(header::IF_MATCH, "*")]).allow_write(current()));
+        // If-Match uses the strong comparison.
+        assert!(!preconditions(&[(header::IF_MATCH, "W/\"abc\"")]).allow_write(current()));
+        assert!(!preconditions(&[(header::IF_MATCH, "\"x\"")]).allow_write(current()));
+    }
+
+    #[test]
+    fn if_match_missing_value() {
+        assert!(!preconditions(&[(header::IF_MATCH, ETAG)]).allow_write(None));
+        assert!(!preconditions(&[(header::IF_MATCH, "*")]).allow_write(None));
+    }
+
+    #[test]
+    fn if_none_match_any_on_creation() {
+        let create = preconditions(&[(header::IF_NONE_MATCH, "*")]);
+        assert!(create.allow_write(None));
+        assert!(!create.allow_write(Some(validators(SystemTime::now()))));
+    }
+
+    #[test]
+    fn no_preconditions() {
+        let none = preconditions(&[]);
+        assert!(!none.not_modified(&validators(SystemTime::now())));
+        assert!(none.allow_write(None));
+        assert!(none.allow_write(Some(validators(SystemTime::now()))));
+    }
+}
@@ -0,0 +1,265 @@
+// Copyright 2023, Antoine Catton
+//
+// Permission to use, copy, modify, and/or distribute this software for any purpose with or without
+// fee is hereby granted, provided that the above copyright notice and this permission notice
+// appear in all copies.
+//
+// THE SOFTWARE IS PROVIDED “AS IS” AND THE AUTHOR DISCLAIMS ALL WARRANTIES WITH REGARD TO THIS
+// SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE
+// AUTHOR BE LIABLE FOR ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+// WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN ACTION OF CONTRACT,
+// NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF OR IN CONNECTION WITH THE USE OR PERFORMANCE
+// OF THIS SOFTWARE.
+
+//! Removal of the expired values in the background.
+//!
+//! The deadlines are kept in an index split in shards by key, where each shard groups the keys by
+//! tick of expiration. Scheduling an expiration only locks one shard for a short time, so writers
+//! never wait for the expiration task. A key has at most one expiration scheduled, scheduling
+//! another one replaces it. All the keys due at once are removed with a single lock of the store.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{btree_map, BTreeMap, HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{self, AtomicU64};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use tokio::sync::Notify;
+use tokio::time::{sleep_until, Duration, Instant};
+
+use crate::aof::{self, Aof};
+use crate::shutdown::Shutdown;
+use crate::{Entry, Kv};
+
+const SHARDS: usize = 16;
+
+/// Resolution of the expirations, the deadlines are rounded up to the next tick.
+const TICK: Duration = Duration::from_millis(10);
+
+#[derive(Debug)]
+struct Timer {
+    /// Generation of the entry which expires, see `Entry`.
+    generation: u64,
+    tick: u64,
+}
+
+#[derive(Debug, Default)]
+struct Shard {
+    timers: HashMap<String, Timer>,
+    ticks: BTreeMap<u64, HashSet<String>>,
+}
+
+impl Shard {
+    fn cancel(&mut self, key: &str) {
+        let Some(timer) = self.timers.remove(key) else {
+            return;
+        };
+        if let btree_map::Entry::Occupied(mut keys) = self.ticks.entry(timer.tick) {
+            keys.get_mut().remove(key);
+            if keys.get().is_empty() {
+                keys.remove();
+            }
+        }
+    }
+
+    /// Take out the keys due at a tick, with their generation.
+    fn due(&mut self, tick: u64) -> Vec<(String, u64)> {
+        let later = self.ticks.split_off(&(tick + 1));
+        let due = std::mem::replace(&mut self.ticks, later);
+        due.into_values()
+            .flatten()
+            .filter_map(|key| {
+                let timer = self.timers.remove(&key)?;
+                Some((key, timer.generation))
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug)]
+pub struct Expirations {
+    start: Instant,
+    shards: Vec<Mutex<Shard>>,
+    /// Tick until which the expiration task sleeps, so that it is woken up when an earlier
+    /// expiration is scheduled.
+    next: AtomicU64,
+    wake: Notify,
+}
+
+impl Default for Expirations {
+    fn default() -> Self {
+        Expirations {
+            start: Instant::now(),
+            shards: (0..SHARDS).map(|_| Default::default()).collect(),
+            next: AtomicU64::new(u64::MAX),
+            wake: Notify::new(),
+        }
+    }
+}
+
+impl Expirations {
+    fn shard(&self, key: &str) -> MutexGuard<'_, Shard> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        self.shards[hasher.finish() as usize % SHARDS]
+            .lock()
+            .expect("Expiration index lock poisoned")
+    }
+
+    /// The first tick at or after a deadline, so that nothing is removed before its deadline.
+    fn tick(&self, deadline: Instant) -> u64 {
+        let elapsed = deadline.saturating_duration_since(self.start);
+        // Saturated rather than wrapped around, which would make a distant deadline already due.
+        u64::try_from(elapsed.as_nanos().div_ceil(TICK.as_nanos())).unwrap_or(u64::MAX)
+    }
+
+    fn instant(&self, tick: u64) -> Instant {
+        self.start + Duration::from_nanos(tick.saturating_mul(TICK.as_nanos() as u64))
+    }
+
+    /// Schedule the expiration of an entry, in place of the one previously scheduled for the same
+    /// key. Without a deadline, the expiration of the key is only cancelled.
+    pub fn schedule(&self, key: &str, generation: u64, deadline: Option<Instant>) {
+        let mut shard = self.shard(key);
+        shard.cancel(key);
+        let Some(deadline) = deadline else {
+            return;
+        };
+        let tick = self.tick(deadline);
+        shard.ticks.entry(tick).or_default().insert(key.to_owned());
+        shard
+            .timers
+            .insert(key.to_owned(), Timer { generation, tick });
+        drop(shard);
+        if tick < self.next.fetch_min(tick, atomic::Ordering::Relaxed) {
+            self.wake.notify_one();
+        }
+    }
+
+    /// The first tick at which something expires.
+    fn first(&self) -> Option<u64> {
+        self.shards
+            .iter()
+            .filter_map(|shard| {
+                let shard = shard.lock().expect("Expiration index lock poisoned");
+                shard.ticks.keys().next().copied()
+            })
+            .min()
+    }
+
+    /// Take out all the keys due by now.
+    fn due(&self) -> Vec<(String, u64)> {
+        let elapsed = Instant::now().saturating_duration_since(self.start);
+        let tick = (elapsed.as_nanos() / TICK.as_nanos()) as u64;
+        self.shards
+            .iter()
+            .flat_map(|shard| {
+                shard
+                    .lock()
+                    .expect("Expiration index lock poisoned")
+                    .due(tick)
+            })
+            .collect()
+    }
+
+    /// Remove the entries which expired, and reschedule the sliding ones read in the meantime.
+    async fn expire(&self, kv: &Kv, aof: Option<&Arc<Aof>>) {
+        let due = self.due();
+        if due.is_empty() {
+            return;
+        }
+        let mut write_kv = kv.write().await;
+        let now = Instant::now();
+        let mut expired = Vec::new();
+        for (key, generation) in due {
+            // The key might have been set again since this expiration was scheduled, in which case
+            // this expiration is stale and the new value must be kept.
+            let entry = write_kv.get(&key).filter(|e| e.generation == generation);
+            match entry.and_then(Entry::expires) {
+                Some(deadline) if deadline > now => self.schedule(&key, generation, Some(deadline)),
+                Some(_) => expired.push(key),
+                None => {}
+            }
+        }
+        if expired.is_empty() {
+            return;
+        }
+        if let Some(aof) = aof {
+            let records = expired
+                .iter()
+                .flat_map(|key| aof::expire_record(key))
+                .collect();
+            if let Err(err) = aof.append(records).await {
+                log::error!("{err:#}");
+            }
+        }
+        for key in &expired {
+            log::debug!("Expiration of key \"{key}\"");
+            write_kv.remove(key);
+        }
+    }
+}
+
+pub async fn expiring(
+    kv: Kv,
+    aof: Option<Arc<Aof>>,
+    expirations: Arc<Expirations>,
+    mut shutdown: Shutdown,
+) {
+    loop {
+        // Reset before looking for the first tick, so that an expiration scheduled in between
+        // wakes the task up.
+        expirations.next.store(u64::MAX, atomic::Ordering::Relaxed);
+        let next = match expirations.first() {
+            Some(tick) => {
+                expirations.next.fetch_min(tick, atomic::Ordering::Relaxed);
+                expirations.instant(tick)
+            }
+            None => Instant::now() + Duration::from_secs(24 * 60 * 60),
+        };
+        tokio::select! {
+            _ = sleep_until(next) => expirations.expire(&kv, aof.as_ref()).await,
+            _ = expirations.wake.notified() => {}
+            _ = shutdown.requested() => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn due_once_reached() {
+        let expirations = Expirations::default();
+        expirations.schedule("a", 1, Some(Instant::now() + Duration::from_millis(15)));
+        assert!(expirations.due().is_empty());
+        tokio::time::advance(Duration::from_millis(20)).await;
+        assert_eq!(expirations.due(), [("a".to_owned(), 1)]);
+        assert!(expirations.due().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rescheduled_and_cancelled() {
+        let expirations = Expirations::default();
+        let deadline = Instant::now() + Duration::from_millis(10);
+        expirations.schedule("a", 1, Some(deadline));
+        expirations.schedule("a", 2, Some(deadline + Duration::from_secs(1)));
+        expirations.schedule("b", 3, Some(deadline));
+        expirations.schedule("b", 4, None);
+        tokio::time::advance(Duration::from_millis(20)).await;
+        assert!(expirations.due().is_empty());
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert_eq!(expirations.due(), [("a".to_owned(), 2)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn distant_deadline_not_due() {
+        let expirations = Expirations::default();
+        let deadline = Instant::now() + Duration::from_secs(184_467_440_737_095_517);
+        expirations.schedule("a", 1, Some(deadline));
+        assert_eq!(expirations.first(), Some(u64::MAX));
+        assert!(expirations.instant(u64::MAX) > Instant::now());
+        assert!(expirations.due().is_empty());
+    }
+}
@@ -0,0 +1,213 @@
+// Copyright 2023, Antoine Catton
+//
+// Permission to use, copy, modify, and/or distribute this software for any purpose with or without
+// fee is hereby granted, provided that the above copyright notice and this permission notice
+// appear in all copies.
+//
+// THE SOFTWARE IS PROVIDED “AS IS” AND THE AUTHOR DISCLAIMS ALL WARRANTIES WITH REGARD TO THIS
+// SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE
+// AUTHOR BE LIABLE FOR ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+// WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN ACTION OF CONTRACT,
+// NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF OR IN CONNECTION WITH THE USE OR PERFORMANCE
+// OF THIS SOFTWARE.
+
+//! Virtual hosts: the host of a request is normalized, and then mapped to the namespace in which
+//! its values are stored.
+
+use std::net::Ipv6Addr;
+use std::str::FromStr;
+
+/// Normalize a host: lowercase, without port nor trailing dot, and with internationalized domain
+/// names in their ASCII form. Returns None for an invalid host.
+pub fn normalize(host: &str) -> Option<String> {
+    if let Some(rest) = host.strip_prefix('[') {
+        // IPv6 address, with an optional port after the bracket.
+        let (addr, port) = rest.split_once(']')?;
+        let port_valid = match port.strip_prefix(':') {
+            Some(port) => port.bytes().all(|b| b.is_ascii_digit()),
+            None => port.is_empty(),
+        };
+        let addr = addr.parse::<Ipv6Addr>().ok().filter(|_| port_valid)?;
+        return Some(format!("[{addr}]"));
+    }
+    let name = match host.rsplit_once(':') {
+        Some((name, port)) if port.bytes().all(|b| b.is_ascii_digit()) => name,
+        _ => host,
+    };
+    let name = name.strip_suffix('.').unwrap_or(name);
+    let name = idna::domain_to_ascii(name).ok()?;
+    let valid = name
+        .bytes()
+        .all(|b| b.is_ascii_alphanumeric() || b"-._".contains(&b));
+    (valid && !name.is_empty()).then_some(name)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Pattern {
+    Exact(String),
+    /// "*.example.com", matches any subdomain of example.com, but not example.com itself.
+    Subdomains(String),
+}
+
+/// A host allowed by --host, with the namespace it's stored in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostRule {
+    pattern: Pattern,
+    /// None stores the values under the host itself.
+    namespace: Option<String>,
+}
+
+impl FromStr for HostRule {
+    type Err = String;
+
+    /// Parse "PATTERN" or "PATTERN=NAMESPACE", where PATTERN is a host or "*.domain".
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (pattern, namespace) = match s.split_once('=') {
+            Some((pattern, namespace)) => (pattern, Some(namespace)),
+            None => (s, None),
+        };
+        let invalid = |host: &str| format!("Invalid host \"{host}\"");
+        let pattern = match pattern.strip_prefix("*.") {
+            Some(domain) => Pattern::Subdomains(normalize(domain).ok_or_else(|| invalid(domain))?),
+            None => Pattern::Exact(normalize(pattern).ok_or_else(|| invalid(pattern))?),
+        };
+        let namespace = namespace
+            .map(|n| normalize(n).ok_or_else(|| invalid(n)))
+            .transpose()?;
+        Ok(HostRule { pattern, namespace })
+    }
+}
+
+impl HostRule {
+    fn matches(&self, host: &str) -> bool {
+        match &self.pattern {
+            Pattern::Exact(name) => name == host,
+            Pattern::Subdomains(domain) => host
+                .strip_suffix(domain.as_str())
+                .map(|sub| sub.len() > 1 && sub.ends_with('.'))
+                .unwrap_or(false),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Hosts {
+    /// Any host is allowed when there are no rules.
+    rules: Vec<HostRule>,
+}
+
+impl Hosts {
+    pub fn new(rules: Vec<HostRule>) -> Self {
+        Hosts { rules }
+    }
+
+    /// The namespace of a normalized host, or None if the host isn't allowed. Exact hosts take
+    /// precedence over wildcards, otherwise the first matching rule wins.
+    pub fn namespace(&self, host: &str) -> Option<String> {
+        if self.rules.is_empty() {
+            return Some(host.to_owned());
+        }
+        let exact = self
+            .rules
+            .iter()
+            .find(|r| matches!(r.pattern, Pattern::Exact(_)) && r.matches(host));
+        let rule = exact.or_else(|| self.rules.iter().find(|r| r.matches(host)))?;
+        Some(rule.namespace.clone().unwrap_or_else(|| host.to_owned()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn namespace(rules: &[&str], host: &str) -> Option<String> {
+        let rules = rules.iter().map(|r| r.parse().unwrap()).collect();
+        Hosts::new(rules).namespace(host)
+    }
+
+    #[test]
+    fn normalize_names() {
+        assert_eq!(normalize("Example.COM"), Some("example.com".to_owned()));
+        assert_eq!(
+            normalize("example.com:8080"),
+            Some("example.com".to_owned())
+        );
+        assert_eq!(normalize("example.com."), Some("example.com".to_owned()));
+        assert_eq!(normalize("example.com.:80"), Some("example.com".to_owned()));
+        assert_eq!(
+            normalize("Bücher.example"),
+            Some("xn--bcher-kva.example".to_owned())
+        );
+        assert_eq!(normalize("127.0.0.1:80"), Some("127.0.0.1".to_owned()));
+    }
+
+    #[test]
+    fn normalize_ipv6() {
+        assert_eq!(normalize("[::1]"), Some("[::1]".to_owned()));
+        assert_eq!(normalize("[::1]:8080"), Some("[::1]".to_owned()));
+        assert_eq!(
+            normalize("[2001:DB8:0::1]"),
+            Some("[2001:db8::1]".to_owned())
+        );
+        assert_eq!(normalize("[a/b]:80"), None);
+        assert_eq!(normalize("[::1"), None);
+        assert_eq!(normalize("[::1]x"), None);
+        assert_eq!(normalize("[::1]:x"), None);
+    }
+
+    #[test]
+    fn normalize_invalid() {
+        assert_eq!(normalize(""), None);
+        assert_eq!(normalize(":80"), None);
+        assert_eq!(normalize("a/b"), None);
+        assert_eq!(normalize("a b"), None);
+        assert_eq!(normalize("a:b"), None);
+    }
+
+    #[test]
+    fn parse_rule() {
+        let rule: HostRule = "*.Example.com=Store.example.com".parse().unwrap();
+        assert_eq!(rule.pattern, Pattern::Subdomains("example.com".to_owned()));
+        assert_eq!(rule.namespace.as_deref(), Some("store.example.com"));
+        assert!("a/b".parse::<HostRule>().is_err());
+        assert!("*.a/b".parse::<HostRule>().is_err());
+        assert!("example.com=a/b".parse::<HostRule>().is_err());
+    }
+
+    #[test]
+    fn subdomains() {
+        let rule: HostRule = "*.example.com".parse().unwrap();
+        assert!(rule.matches("www.example.com"));
+        assert!(rule.matches("a.b.example.com"));
+        assert!(!rule.matches("example.com"));
+        assert!(!rule.matches(".example.com"));
+        assert!(!rule.matches("notexample.com"));
+        assert!(!rule.matches("example.com.evil"));
+    }
+
+    #[test]
+    fn namespaces() {
+        let rules = [
+            "*.example.com=example.com",
+            "www.example.com",
+            "example.net",
+        ];
+        assert_eq!(
+            namespace(&rules, "a.example.com"),
+            Some("example.com".to_owned())
+        );
+        assert_eq!(
+            namespace(&rules, "www.example.com"),
+            Some("www.example.com".to_owned())
+        );
+        assert_eq!(
+            namespace(&rules, "example.net"),
+            Some("example.net".to_owned())
+        );
+        assert_eq!(namespace(&rules, "example.com"), None);
+        assert_eq!(
+            namespace(&[], "example.org"),
+            Some("example.org".to_owned())
+        );
+    }
+}
@@ -0,0 +1,120 @@
+// Copyright 2023, Antoine Catton
+//
+// Permission to use, copy, modify, and/or distribute this software for any purpose with or without
+// fee is hereby granted, provided that the above copyright notice and this permission notice
+// appear in all copies.
+//
+// THE SOFTWARE IS PROVIDED “AS IS” AND THE AUTHOR DISCLAIMS ALL WARRANTIES WITH REGARD TO THIS
+// SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE
+// AUTHOR BE LIABLE FOR ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+// WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN ACTION OF CONTRACT,
+// NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF OR IN CONNECTION WITH THE USE OR PERFORMANCE
+// OF THIS SOFTWARE.
+
+//! Reading request bodies, with a maximum size and a timeout, so that a client can neither exhaust
+//! the memory with a huge body nor hold a connection forever by sending it slowly.
+
+use hyper::body::HttpBody;
+use hyper::{Body, StatusCode};
+use tokio::time::{self, Duration, Instant};
+
+#[derive(Debug)]
+pub enum Error {
+    TooLarge(usize),
+    Timeout(Duration),
+    Read(hyper::Error),
+}
+
+impl Error {
+    /// The status to answer with, or None if the client is gone.
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            Error::TooLarge(_) => Some(StatusCode::PAYLOAD_TOO_LARGE),
+            Error::Timeout(_) => Some(StatusCode::REQUEST_TIMEOUT),
+            Error::Read(_) => None,
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            Error::TooLarge(max) => format!("Body larger than {max} bytes"),
+            Error::Timeout(timeout) => format!("Body not received within {timeout:?}"),
+            Error::Read(err) => format!("Could not read body: {err}"),
+        }
+    }
+}
+
+/// Read a whole body. The announced Content-Length is checked before reading anything, and the
+/// size of what is actually received is checked while reading.
+pub async fn read(body: &mut Body, max: usize, timeout: Duration) -> Result<Vec<u8>, Error> {
+    let announced = body.size_hint().lower();
+    if announced > max as u64 {
+        return Err(Error::TooLarge(max));
+    }
+    let deadline = Instant::now() + timeout;
+    let mut content = Vec::with_capacity(announced as usize);
+    loop {
+        let chunk = match time::timeout_at(deadline, body.data()).await {
+            Err(_) => return Err(Error::Timeout(timeout)),
+            Ok(None) => return Ok(content),
+            Ok(Some(chunk)) => chunk.map_err(Error::Read)?,
+        };
+        if content.len() + chunk.len() > max {
+            return Err(Error::TooLarge(max));
+        }
+        content.extend_from_slice(&chunk);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use hyper::body::Bytes;
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    #[tokio::test]
+    async fn whole_body() {
+        let mut body = Body::from("value");
+        assert_eq!(read(&mut body, 5, TIMEOUT).await.unwrap(), b"value");
+    }
+
+    #[tokio::test]
+    async fn announced_too_large() {
+        let mut body = Body::from("value");
+        let err = read(&mut body, 4, TIMEOUT).await.unwrap_err();
+        assert!(matches!(err, Error::TooLarge(4)));
+        // Nothing was read.
+        assert_eq!(body.data().await.unwrap().unwrap(), "value");
+    }
+
+    #[tokio::test]
+    async fn streamed_too_large() {
+        // A streamed body doesn't announce its length.
+        let (mut sender, mut body) = Body::channel();
+        tokio::spawn(async move {
+            for _ in 0..3 {
+                if sender.send_data(Bytes::from("12")).await.is_err() {
+                    break;
+                }
+            }
+        });
+        let err = read(&mut body, 5, TIMEOUT).await.unwrap_err();
+        assert!(matches!(err, Error::TooLarge(5)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn timeout() {
+        let (mut sender, mut body) = Body::channel();
+        let start = Instant::now();
+        tokio::spawn(async move {
+            sender.send_data(Bytes::from("12")).await.unwrap();
+            // The body is never finished.
+            time::sleep(2 * TIMEOUT).await;
+        });
+        let err = read(&mut body, 5, TIMEOUT).await.unwrap_err();
+        assert!(matches!(err, Error::Timeout(TIMEOUT)));
+        assert_eq!(start.elapsed(), TIMEOUT);
+    }
+}
@@ -0,0 +1,272 @@
+// Copyright 2023, Antoine Catton
+//
+// Permission to use, copy, modify, and/or distribute this software for any purpose with or without
+// fee is hereby granted, provided that the above copyright notice and this permission notice
+// appear in all copies.
+//
+// THE SOFTWARE IS PROVIDED “AS IS” AND THE AUTHOR DISCLAIMS ALL WARRANTIES WITH REGARD TO THIS
+// SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE
+// AUTHOR BE LIABLE FOR ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+// WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN ACTION OF CONTRACT,
+// NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF OR IN CONNECTION WITH THE USE OR PERFORMANCE
+// OF THIS SOFTWARE.
+
+//! Addresses to listen on: TCP, Unix domain sockets or sockets passed by systemd, all of them with
+//! or without TLS.
+
+use std::ffi::CString;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::os::fd::OwnedFd;
+use std::os::unix::fs::{chown, FileTypeExt, PermissionsExt};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+use tokio::net::{TcpListener, UnixListener};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Socket {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+    /// Sockets passed by systemd with socket activation, by name.
+    Systemd(String),
+}
+
+/// What the clients of a listener are allowed to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Mode {
+    /// GET, HEAD and OPTIONS.
+    ReadOnly,
+    /// Also PUT, PATCH and DELETE.
+    ReadWrite,
+    /// Also the operations on many keys at once, like recursive deletion.
+    Admin,
+}
+
+impl Mode {
+    const ALL: [(&'static str, Mode); 3] = [
+        ("read-only", Mode::ReadOnly),
+        ("read-write", Mode::ReadWrite),
+        ("admin", Mode::Admin),
+    ];
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (name, _) = Mode::ALL
+            .iter()
+            .find(|(_, m)| m == self)
+            .expect("Unknown mode");
+        write!(f, "{name}")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Address {
+    pub socket: Socket,
+    pub tls: bool,
+    pub mode: Mode,
+}
+
+impl FromStr for Address {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (s, mode) = Mode::ALL
+            .iter()
+            .find_map(|(name, mode)| {
+                let rest = s.strip_prefix(name)?.strip_prefix('=')?;
+                Some((rest, *mode))
+            })
+            .unwrap_or((s, Mode::Admin));
+        let (s, tls) = match s.strip_prefix("tls:") {
+            Some(s) => (s, true),
+            None => (s, false),
+        };
+        let socket = if let Some(path) = s.strip_prefix("unix:") {
+            Socket::Unix(path.into())
+        } else if let Some(name) = s.strip_prefix("systemd:") {
+            Socket::Systemd(name.to_owned())
+        } else {
+            let addr = s.strip_prefix("tcp:").unwrap_or(s);
+            Socket::Tcp(
+                addr.parse()
+                    .map_err(|_| format!("Invalid socket address \"{addr}\""))?,
+            )
+        };
+        Ok(Address { socket, tls, mode })
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{mode}=", mode = self.mode)?;
+        if self.tls {
+            write!(f, "tls:")?;
+        }
+        match &self.socket {
+            Socket::Tcp(addr) => write!(f, "tcp:{addr}"),
+            Socket::Unix(path) => write!(f, "unix:{path}", path = path.display()),
+            Socket::Systemd(name) => write!(f, "systemd:{name}"),
+        }
+    }
+}
+
+/// Owner and permissions given to the Unix domain sockets.
+#[derive(Debug, Clone, Default)]
+pub struct UnixPermissions {
+    pub mode: Option<u32>,
+    pub owner: Option<Owner>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Owner {
+    uid: Option<u32>,
+    gid: Option<u32>,
+}
+
+fn lookup_user(name: &str) -> Option<u32> {
+    let name = CString::new(name).ok()?;
+    // SAFETY: getpwnam() is given a valid NUL-terminated string, and the returned pointer is
+    // checked before being dereferenced and not kept around.
+    unsafe {
+        let passwd = libc::getpwnam(name.as_ptr());
+        (!passwd.is_null()).then(|| (*passwd).pw_uid)
+    }
+}
+
+fn lookup_group(name: &str) -> Option<u32> {
+    let name = CString::new(name).ok()?;
+    // SAFETY: same as getpwnam() above.
+    unsafe {
+        let group = libc::getgrnam(name.as_ptr());
+        (!group.is_null()).then(|| (*group).gr_gid)
+    }
+}
+
+impl FromStr for Owner {
+    type Err = String;
+
+    /// Parse "user", "user:group" or ":group", where user and group are either names or ids.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (user, group) = s.split_once(':').unwrap_or((s, ""));
+        let uid = Some(user)
+            .filter(|u| !u.is_empty())
+            .map(|u| {
+                u.parse()
+                    .ok()
+                    .or_else(|| lookup_user(u))
+                    .ok_or_else(|| format!("Unknown user \"{u}\""))
+            })
+            .transpose()?;
+        let gid = Some(group)
+            .filter(|g| !g.is_empty())
+            .map(|g| {
+                g.parse()
+                    .ok()
+                    .or_else(|| lookup_group(g))
+                    .ok_or_else(|| format!("Unknown group \"{g}\""))
+            })
+            .transpose()?;
+        Ok(Owner { uid, gid })
+    }
+}
+
+pub fn parse_mode(s: &str) -> Result<u32, String> {
+    u32::from_str_radix(s, 8).map_err(|_| format!("Invalid octal mode \"{s}\""))
+}
+
+#[derive(Debug)]
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+/// Remove a socket left behind by a previous instance which didn't exit cleanly. A socket on which
+/// something still accepts connections is left untouched, so that binding fails.
+fn remove_stale_socket(path: &PathBuf) -> Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err).context("Could not inspect existing socket"),
+    };
+    if !metadata.file_type().is_socket() {
+        anyhow::bail!("{path} exists and is not a socket", path = path.display());
+    }
+    if let Err(err) = UnixStream::connect(path) {
+        if err.kind() == io::ErrorKind::ConnectionRefused {
+            log::info!("Removing stale socket {path}", path = path.display());
+            fs::remove_file(path).context("Could not remove stale socket")?;
+        }
+    }
+    Ok(())
+}
+
+/// Turn a socket passed by systemd into a listener.
+fn from_fd(fd: OwnedFd) -> Result<Listener> {
+    let tcp = std::net::TcpListener::from(fd);
+    if tcp.local_addr().is_ok() {
+        tcp.set_nonblocking(true)?;
+        return Ok(Listener::Tcp(TcpListener::from_std(tcp)?));
+    }
+    let unix = std::os::unix::net::UnixListener::from(OwnedFd::from(tcp));
+    unix.local_addr()
+        .context("Socket passed by systemd is neither TCP nor Unix")?;
+    unix.set_nonblocking(true)?;
+    Ok(Listener::Unix(UnixListener::from_std(unix)?))
+}
+
+/// Bind on an address, or take the matching sockets from the ones passed by systemd.
+pub async fn bind(
+    address: &Address,
+    permissions: &UnixPermissions,
+    inherited: &mut Vec<(String, OwnedFd)>,
+) -> Result<Vec<Listener>> {
+    match &address.socket {
+        Socket::Tcp(addr) => TcpListener::bind(addr)
+            .await
+            .map(|l| vec![Listener::Tcp(l)])
+            .with_context(|| format!("Could not bind on {address}")),
+        Socket::Unix(path) => {
+            remove_stale_socket(path)?;
+            let listener =
+                UnixListener::bind(path).with_context(|| format!("Could not bind on {address}"))?;
+            if let Some(mode) = permissions.mode {
+                fs::set_permissions(path, fs::Permissions::from_mode(mode))
+                    .with_context(|| format!("Could not set mode of {address}"))?;
+            }
+            if let Some(owner) = &permissions.owner {
+                chown(path, owner.uid, owner.gid)
+                    .with_context(|| format!("Could not set owner of {address}"))?;
+            }
+            Ok(vec![Listener::Unix(listener)])
+        }
+        Socket::Systemd(name) => {
+            let (matching, others) = inherited.drain(..).partition(|(n, _)| n == name);
+            *inherited = others;
+            let listeners = matching
+                .into_iter()
+                .map(|(_, fd)| from_fd(fd))
+                .collect::<Result<Vec<_>>>()
+                .with_context(|| format!("Could not use {address}"))?;
+            anyhow::ensure!(
+                !listeners.is_empty(),
+                "No socket passed by systemd for {address}"
+            );
+            Ok(listeners)
+        }
+    }
+}
+
+/// Remove the socket file of a Unix domain socket listener, when shutting down.
+pub fn cleanup(address: &Address) {
+    if let Socket::Unix(path) = &address.socket {
+        if let Err(err) = fs::remove_file(path) {
+            log::warn!("Could not remove {address}: {err}");
+        }
+    }
+}
@@ -0,0 +1,239 @@
+// Copyright 2023, Antoine Catton
+//
+// Permission to use, copy, modify, and/or distribute this software for any purpose with or without
+// fee is hereby granted, provided that the above copyright notice and this permission notice
+// appear in all copies.
+//
+// THE SOFTWARE IS PROVIDED “AS IS” AND THE AUTHOR DISCLAIMS ALL WARRANTIES WITH REGARD TO THIS
+// SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE
+// AUTHOR BE LIABLE FOR ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+// WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN ACTION OF CONTRACT,
+// NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF OR IN CONNECTION WITH THE USE OR PERFORMANCE
+// OF THIS SOFTWARE.
+
+//! Listing of the keys under a prefix, with "GET /prefix/?list".
+
+use std::ops::Bound;
+
+use anyhow::{Context, Result};
+use http::header;
+use hyper::{Body, Response, StatusCode};
+use serde_json::{json, Value};
+use tokio::time::Instant;
+
+use crate::{snapshot, State};
+
+const DEFAULT_LIMIT: usize = 1000;
+
+/// Parameters of a listing, from the query string.
+#[derive(Debug, Default)]
+pub struct Params {
+    /// Only list the keys after this path.
+    cursor: Option<String>,
+    limit: Option<usize>,
+    /// Group the keys containing the delimiter after the prefix, like directories.
+    delimiter: Option<String>,
+}
+
+impl Params {
+    /// Parse the query string, None if it doesn't ask for a listing.
+    pub fn from_query(query: &str) -> Option<Result<Self, String>> {
+        let mut list = false;
+        let mut params = Params::default();
+        for (name, value) in form_urlencoded::parse(query.as_bytes()) {
+            match name.as_ref() {
+                "list" => list = true,
+                "cursor" => params.cursor = Some(value.into_owned()),
+                "delimiter" if !value.is_empty() => params.delimiter = Some(value.into_owned()),
+                "limit" => match value.parse() {
+                    Ok(limit) if limit > 0 => params.limit = Some(limit),
+                    _ => return Some(Err("limit must be a positive number".to_owned())),
+                },
+                _ => (),
+            }
+        }
+        list.then_some(Ok(params))
+    }
+}
+
+/// List the keys of a host starting with a path prefix.
+pub async fn list(
+    state: State,
+    host: &str,
+    prefix: &str,
+    params: Params,
+) -> Result<Response<Body>> {
+    let full_prefix = format!("{host}{prefix}");
+    let start = match &params.cursor {
+        Some(cursor) if cursor.as_str() >= prefix => Bound::Excluded(format!("{host}{cursor}")),
+        _ => Bound::Included(full_prefix.clone()),
+    };
+    let limit = params.limit.unwrap_or(DEFAULT_LIMIT).min(DEFAULT_LIMIT);
+
+    let mut keys = Vec::new();
+    let mut prefixes: Vec<String> = Vec::new();
+    let mut last = None;
+    let mut truncated = false;
+
+    let read_kv = state.kv.read().await;
+    let now = Instant::now();
+    for (key, entry) in read_kv.range((start, Bound::Unbounded)) {
+        if !key.starts_with(&full_prefix) {
+            break;
+        }
+        // Expired values might not have been removed yet.
+        if entry.expired(now) {
+            continue;
+        }
+        let path = &key[host.len()..];
+        let directory = params.delimiter.as_deref().and_then(|delimiter| {
+            let rest = &path[prefix.len()..];
+            rest.find(delimiter)
+                .map(|i| &path[..prefix.len() + i + delimiter.len()])
+        });
+        if let Some(directory) = directory {
+            // The previous page might have ended in the middle of this directory.
+            if params.cursor.as_deref() == Some(directory) {
+                continue;
+            }
+            if prefixes.last().map(String::as_str) == Some(directory) {
+                continue;
+            }
+        }
+        if keys.len() + prefixes.len() >= limit {
+            truncated = true;
+            break;
+        }
+        match directory {
+            Some(directory) => {
+                prefixes.push(directory.to_owned());
+                last = Some(directory);
+            }
+            None => {
+                keys.push(json!({
+                    "path": path,
+                    "size": entry.value.len(),
+                    "content_type": entry
+                        .headers
+                        .get(header::CONTENT_TYPE)
+                        .and_then(|v| v.to_str().ok()),
+                    "expires_at_ms": entry.expires().map(snapshot::deadline_millis),
+                }));
+                last = Some(path);
+            }
+        }
+    }
+
+    let next_cursor = if truncated { last } else { None };
+    let body = json!({
+        "keys": keys,
+        "prefixes": prefixes,
+        "next_cursor": next_cursor,
+    });
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Value::to_string(&body).into())
+        .context("Could not build listing response")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::time::Duration;
+
+    use crate::tests::{entry, state};
+
+    async fn store() -> State {
+        let state = state();
+        let mut write_kv = state.kv.write().await;
+        for key in ["/a/1", "/a/2", "/b/1", "/b/2", "/c", "/d/1", "/f"] {
+            let entry = entry(&state, b"value", None);
+            state.insert(&mut write_kv, format!("example.com{key}"), entry);
+        }
+        let expired = entry(&state, b"value", Some(Instant::now()));
+        state.insert(&mut write_kv, "example.com/e".to_owned(), expired);
+        let other = entry(&state, b"value", None);
+        state.insert(&mut write_kv, "example.org/a".to_owned(), other);
+        drop(write_kv);
+        state
+    }
+
+    async fn page(state: &State, prefix: &str, query: &str) -> Value {
+        let params = Params::from_query(query).unwrap().unwrap();
+        let response = list(state.clone(), "example.com", prefix, params)
+            .await
+            .unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    /// Walk all the pages of a listing, and return the keys and prefixes of each page.
+    async fn walk(state: &State, prefix: &str, query: &str) -> Vec<(Vec<String>, Vec<String>)> {
+        let mut pages = Vec::new();
+        let mut cursor = None;
+        loop {
+            let query = match &cursor {
+                Some(cursor) => format!("{query}&cursor={cursor}"),
+                None => query.to_owned(),
+            };
+            let page = page(state, prefix, &query).await;
+            let keys = page["keys"].as_array().unwrap().iter();
+            let keys = keys
+                .map(|k| k["path"].as_str().unwrap().to_owned())
+                .collect();
+            let prefixes = page["prefixes"].as_array().unwrap().iter();
+            let prefixes = prefixes.map(|p| p.as_str().unwrap().to_owned()).collect();
+            pages.push((keys, prefixes));
+            match page["next_cursor"].as_str() {
+                Some(next) => cursor = Some(next.to_owned()),
+                None => return pages,
+            }
+            assert!(pages.len() < 10, "Listing never ends");
+        }
+    }
+
+    fn strings(strings: &[&str]) -> Vec<String> {
+        strings.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pages() {
+        let state = store().await;
+        tokio::time::advance(Duration::from_millis(1)).await;
+        let pages = walk(&state, "/", "list&limit=2").await;
+        assert_eq!(
+            pages,
+            [
+                (strings(&["/a/1", "/a/2"]), strings(&[])),
+                (strings(&["/b/1", "/b/2"]), strings(&[])),
+                (strings(&["/c", "/d/1"]), strings(&[])),
+                (strings(&["/f"]), strings(&[])),
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pages_with_delimiter() {
+        let state = store().await;
+        tokio::time::advance(Duration::from_millis(1)).await;
+        // The second page starts in the middle of "/b/", which was already listed.
+        let pages = walk(&state, "/", "list&limit=2&delimiter=/").await;
+        assert_eq!(
+            pages,
+            [
+                (strings(&[]), strings(&["/a/", "/b/"])),
+                (strings(&["/c"]), strings(&["/d/"])),
+                (strings(&["/f"]), strings(&[])),
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cursor_before_prefix() {
+        let state = store().await;
+        let pages = walk(&state, "/b/", "list&limit=2&cursor=/a").await;
+        assert_eq!(pages, [(strings(&["/b/1", "/b/2"]), strings(&[]))]);
+    }
+}
@@ -11,41 +11,224 @@
 // NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF OR IN CONNECTION WITH THE USE OR PERFORMANCE
 // OF THIS SOFTWARE.
 
-use std::cmp::Ordering;
-use std::collections::BinaryHeap;
-use std::collections::HashMap;
-use std::convert::Infallible;
-use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{self, AtomicU64};
 use std::sync::Arc;
+use std::time::SystemTime;
 
 use anyhow::{Context, Result};
 use clap::Parser;
-use http::header;
-use hyper::service::{make_service_fn, service_fn};
-use hyper::{body, Body, Method, Request, Response, Server, StatusCode};
+use http::header::{self, HeaderMap, HeaderName};
+use http::response;
+use hyper::body::HttpBody;
+use hyper::server::conn::Http;
+use hyper::{Body, Method, Request, Response, StatusCode};
 use simple_logger::SimpleLogger;
-use tokio::sync::mpsc;
+use tokio::signal::unix::{signal, SignalKind};
 use tokio::sync::RwLock;
-use tokio::task;
-use tokio::time::{sleep_until, Duration, Instant};
+use tokio::task::{self, JoinSet};
+use tokio::time::{Duration, Instant};
 
-#[derive(Debug, PartialEq, Eq)]
-struct Expiration {
-    key: String,
-    deadline: Instant,
+use crate::aof::{Aof, Fsync};
+use crate::auth::{Auth, Decision};
+use crate::conditional::{Preconditions, Validators};
+use crate::expiry::Expirations;
+use crate::hosts::{HostRule, Hosts};
+use crate::listener::{Address, Mode, Owner, UnixPermissions};
+use crate::presign::{Presign, Signature};
+use crate::quota::{QuotaRule, Quotas};
+use crate::shutdown::Drain;
+use crate::store::{Eviction, MemoryLimit, Store, Usage};
+use crate::tls::{Resolver, TlsFiles};
+use crate::ttl::Expire;
+
+mod aof;
+mod auth;
+mod conditional;
+mod expiry;
+mod hosts;
+mod ingest;
+mod listener;
+mod listing;
+mod presign;
+mod quota;
+mod server;
+mod shutdown;
+mod snapshot;
+mod store;
+mod systemd;
+mod tls;
+mod ttl;
+
+/// A stored value. The generation is unique across the whole store, it is used to make sure that an
+/// expiration only removes the value it was created for, and not a value set later on the same key.
+#[derive(Debug)]
+struct Entry {
+    value: Vec<u8>,
+    generation: u64,
+    headers: HeaderMap,
+    etag: String,
+    modified: SystemTime,
+    deadline: Option<Instant>,
+    sliding: Option<Sliding>,
+    usage: Usage,
+}
+
+impl Entry {
+    fn validators(&self) -> Validators<'_> {
+        Validators {
+            etag: &self.etag,
+            modified: self.modified,
+        }
+    }
+
+    /// When the value expires, taking the reads into account for a sliding expiration.
+    fn expires(&self) -> Option<Instant> {
+        match &self.sliding {
+            Some(sliding) => Some(sliding.deadline()),
+            None => self.deadline,
+        }
+    }
+
+    fn expired(&self, now: Instant) -> bool {
+        self.expires().is_some_and(|deadline| deadline <= now)
+    }
+}
+
+/// An expiration postponed by every read, to the time of the read plus the TTL.
+#[derive(Debug)]
+struct Sliding {
+    ttl: Duration,
+    /// Time of the last read. A mutex is used so that reads can update it while only holding the
+    /// read lock of the store.
+    read: std::sync::Mutex<Instant>,
 }
 
+impl Sliding {
+    fn new(ttl: Duration, deadline: Instant) -> Self {
+        let read = deadline.checked_sub(ttl).unwrap_or_else(Instant::now);
+        Sliding {
+            ttl,
+            read: std::sync::Mutex::new(read),
+        }
+    }
+
+    fn deadline(&self) -> Instant {
+        *self.read.lock().expect("Sliding expiration lock poisoned") + self.ttl
+    }
+
+    fn touch(&self) {
+        *self.read.lock().expect("Sliding expiration lock poisoned") = Instant::now();
+    }
+}
+
+/// Methods allowed on a key, depending on the mode of the listener.
+fn allowed_methods(mode: Mode) -> &'static str {
+    match mode {
+        Mode::ReadOnly => "GET, HEAD, OPTIONS",
+        _ => "GET, HEAD, PUT, PATCH, DELETE, OPTIONS",
+    }
+}
+
+/// Headers of a PUT request which are stored with the value and replayed on GET.
+const STORED_HEADERS: [HeaderName; 4] = [
+    header::CONTENT_TYPE,
+    header::CONTENT_ENCODING,
+    header::CONTENT_DISPOSITION,
+    header::CACHE_CONTROL,
+];
+
+type Kv = Arc<RwLock<Store>>;
+
 #[derive(Debug, Clone)]
 struct State {
-    kv: Arc<RwLock<HashMap<String, Vec<u8>>>>,
-    expirations: mpsc::Sender<Expiration>,
-    default_expiration: u64,
+    kv: Kv,
+    generations: Arc<AtomicU64>,
+    expirations: Arc<Expirations>,
+    default_expiration: Expire,
+    /// Also take the expiration from "Cache-Control: max-age".
+    cache_control_expiration: bool,
+    meta_headers: Arc<Vec<HeaderName>>,
+    aof: Option<Arc<Aof>>,
+    auth: Option<Arc<Auth>>,
+    presign: Option<Arc<Presign>>,
+    hosts: Arc<Hosts>,
+    memory_limit: Option<MemoryLimit>,
+    quotas: Arc<Quotas>,
+    max_body_size: usize,
+    body_timeout: Duration,
+}
+
+impl State {
+    /// Select the headers of a PUT request which need to be stored along with the value.
+    fn stored_headers(&self, headers: &HeaderMap) -> HeaderMap {
+        STORED_HEADERS
+            .iter()
+            .chain(self.meta_headers.iter())
+            .flat_map(|name| {
+                headers
+                    .get_all(name)
+                    .iter()
+                    .map(move |value| (name.clone(), value.clone()))
+            })
+            .collect()
+    }
+
+    fn next_generation(&self) -> u64 {
+        self.generations.fetch_add(1, atomic::Ordering::Relaxed)
+    }
+
+    /// Store an entry in the key-value store, and schedule its expiration if it has a deadline.
+    fn insert(&self, kv: &mut Store, key: String, entry: Entry) {
+        self.expirations
+            .schedule(&key, entry.generation, entry.deadline);
+        kv.insert(key, entry);
+    }
+
+    /// Change when an entry expires, and schedule its new expiration.
+    fn set_deadline(
+        &self,
+        kv: &mut Store,
+        key: &str,
+        deadline: Option<Instant>,
+        sliding: Option<Duration>,
+    ) {
+        let generation = self.next_generation();
+        if kv.set_deadline(key, deadline, sliding, generation) {
+            self.expirations.schedule(key, generation, deadline);
+        }
+    }
+}
+
+/// Add the headers telling when a value expires: "Expires" as an HTTP date, "X-Expires-At" in
+/// milliseconds since the Unix epoch, and "X-TTL-ms" in milliseconds from now.
+fn with_expiration(builder: response::Builder, deadline: Option<Instant>) -> response::Builder {
+    match deadline {
+        None => builder,
+        Some(deadline) => {
+            let ttl = deadline.saturating_duration_since(Instant::now());
+            builder
+                .header(
+                    header::EXPIRES,
+                    httpdate::fmt_http_date(SystemTime::now() + ttl),
+                )
+                .header("X-Expires-At", snapshot::deadline_millis(deadline))
+                .header("X-TTL-ms", ttl.as_millis() as u64)
+        }
+    }
+}
+
+fn precondition_failed() -> Result<Response<Body>> {
+    Response::builder()
+        .status(StatusCode::PRECONDITION_FAILED)
+        .body(Body::empty())
+        .context("Could not build precondition failed response")
 }
 
-async fn get(state: State, key: String) -> Result<Response<Body>> {
+async fn get(state: State, key: String, preconditions: Preconditions) -> Result<Response<Body>> {
     let read_kv = state.kv.read().await;
-    let value = match read_kv.get(&key) {
-        Some(value) => value,
+    let entry = match read_kv.touch(&key) {
+        Some(entry) => entry,
         None => {
             return Response::builder()
                 .status(StatusCode::NOT_FOUND)
@@ -54,52 +237,287 @@ async fn get(state: State, key: String) -> Result<Response<Body>> {
         }
     };
 
-    Ok(Response::new(value.to_vec().into()))
+    if let Some(sliding) = &entry.sliding {
+        sliding.touch();
+    }
+    let mut builder = with_expiration(Response::builder(), entry.expires())
+        .header(header::ETAG, &entry.etag)
+        .header(
+            header::LAST_MODIFIED,
+            httpdate::fmt_http_date(entry.modified),
+        );
+    if entry.sliding.is_some() {
+        builder = builder.header("X-Expire-Mode", "sliding");
+    }
+    let mut response = if preconditions.not_modified(&entry.validators()) {
+        builder
+            .status(StatusCode::NOT_MODIFIED)
+            .body(Body::empty())
+            .context("Could not build not modified response")?
+    } else {
+        builder
+            .status(StatusCode::OK)
+            .body(entry.value.to_vec().into())
+            .context("Could not build response")?
+    };
+    response.headers_mut().extend(entry.headers.clone());
+    Ok(response)
+}
+
+async fn head(state: State, key: String, preconditions: Preconditions) -> Result<Response<Body>> {
+    let (mut parts, body) = get(state, key, preconditions).await?.into_parts();
+    if let (StatusCode::OK, Some(length)) = (parts.status, body.size_hint().exact()) {
+        parts.headers.insert(header::CONTENT_LENGTH, length.into());
+    }
+    Ok(Response::from_parts(parts, Body::empty()))
+}
+
+fn options(mode: Mode) -> Result<Response<Body>> {
+    Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .header(header::ALLOW, allowed_methods(mode))
+        .body(Body::empty())
+        .context("Could not build options response")
 }
 
 async fn set(
     state: State,
     key: String,
     value: &[u8],
-    expiration_ms: u64,
+    headers: HeaderMap,
+    expire: Expire,
+    sliding: bool,
+    preconditions: Preconditions,
 ) -> Result<Response<Body>> {
+    let etag = conditional::etag(value);
+    let modified = SystemTime::now();
     let mut write_kv = state.kv.write().await;
-    write_kv.insert(key.clone(), value.to_vec());
-    if expiration_ms > 0 {
-        log::trace!(
-            "{key} expire in {expiration}ms",
-            key = &key,
-            expiration = expiration_ms
-        );
-        state
-            .expirations
-            .send(Expiration {
-                deadline: Instant::now() + Duration::from_millis(expiration_ms),
-                key,
-            })
-            .await
-            .context("Could not trigger expiration in the background")?;
+    if !preconditions.allow_write(write_kv.live(&key).map(Entry::validators)) {
+        return precondition_failed();
     }
-    Response::builder()
+    let host = store::host_of(&key);
+    let quota = state.quotas.check(
+        host,
+        write_kv.host_usage(host),
+        key.len(),
+        value.len(),
+        write_kv.entry_size(&key),
+    );
+    if let Err(exceeded) = quota {
+        let message = exceeded.message(host);
+        log::warn!("{message}, refusing to set \"{key}\"");
+        return Response::builder()
+            .status(exceeded.status())
+            .body(message.into())
+            .context("Could not build quota exceeded response");
+    }
+    let victims = match state.memory_limit {
+        Some(limit) => match write_kv.victims(limit, &key, value.len()) {
+            Some(victims) => victims,
+            None => {
+                log::warn!(
+                    "Memory limit reached ({used} bytes used), refusing to set \"{key}\"",
+                    used = write_kv.used()
+                );
+                return Response::builder()
+                    .status(StatusCode::INSUFFICIENT_STORAGE)
+                    .body("Memory limit reached".into())
+                    .context("Could not build insufficient storage response");
+            }
+        },
+        None => Vec::new(),
+    };
+    if let Expire::In(duration) = expire {
+        log::trace!("{key} expire in {duration:?}", key = &key);
+    }
+    let deadline = expire.deadline();
+    let sliding = match (sliding, expire, deadline) {
+        (true, Expire::In(ttl), Some(deadline)) => Some(Sliding::new(ttl, deadline)),
+        _ => None,
+    };
+    let entry = Entry {
+        value: value.to_vec(),
+        generation: state.next_generation(),
+        headers,
+        etag: etag.clone(),
+        modified,
+        deadline,
+        sliding,
+        usage: Default::default(),
+    };
+    if let Some(aof) = &state.aof {
+        let mut records: Vec<u8> = victims.iter().flat_map(|v| aof::delete_record(v)).collect();
+        records.extend(aof::set_record(&key, &entry));
+        aof.append(records).await?;
+    }
+    for victim in &victims {
+        log::info!("Evicted \"{victim}\" to make room for \"{key}\"");
+        write_kv.remove(victim);
+    }
+    state.insert(&mut write_kv, key, entry);
+    let mut builder = with_expiration(Response::builder(), deadline)
         .status(StatusCode::OK)
-        .header("X-memoryhttpd-action", "set")
+        .header("X-memoryhttpd-action", "set");
+    if !victims.is_empty() {
+        builder = builder.header("X-memoryhttpd-evicted", victims.len());
+    }
+    builder
+        .header(header::ETAG, etag)
+        .header(header::LAST_MODIFIED, httpdate::fmt_http_date(modified))
         .body(value.to_vec().into())
         .context("Could not build response")
 }
 
-async fn delete(state: State, key: String) -> Result<Response<Body>> {
+async fn delete(state: State, key: String, preconditions: Preconditions) -> Result<Response<Body>> {
     let mut write_kv = state.kv.write().await;
+    if !preconditions.allow_write(write_kv.live(&key).map(Entry::validators)) {
+        return precondition_failed();
+    }
+    if let (Some(aof), true) = (&state.aof, write_kv.contains_key(&key)) {
+        aof.append(aof::delete_record(&key)).await?;
+    }
     write_kv.remove(&key);
     Ok(Response::new(Body::empty()))
 }
 
-async fn handler(state: State, mut req: Request<Body>) -> Result<Response<Body>> {
+/// A change of the expiration of a value.
+#[derive(Debug, Clone, Copy)]
+enum Ttl {
+    Set(Expire),
+    /// Postpone the current expiration by a number of milliseconds.
+    Extend(u64),
+}
+
+/// Change when a value expires, without changing the value.
+async fn patch(
+    state: State,
+    key: String,
+    ttl: Ttl,
+    preconditions: Preconditions,
+) -> Result<Response<Body>> {
+    let mut write_kv = state.kv.write().await;
+    let entry = match write_kv.live(&key) {
+        Some(entry) => entry,
+        None => {
+            return Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::empty())
+                .context("Could not build not found response");
+        }
+    };
+    if !preconditions.allow_write(Some(entry.validators())) {
+        return precondition_failed();
+    }
+    let deadline = match (ttl, entry.expires()) {
+        (Ttl::Set(expire), _) => expire.deadline(),
+        (Ttl::Extend(ms), Some(deadline)) => {
+            let max = Instant::now() + ttl::MAX_EXPIRATION;
+            match deadline
+                .checked_add(Duration::from_millis(ms))
+                .filter(|&deadline| deadline <= max)
+            {
+                Some(deadline) => Some(deadline),
+                None => {
+                    return Response::builder()
+                        .status(StatusCode::BAD_REQUEST)
+                        .body("X-Extend-ms is too large".into())
+                        .context("Could not build bad request for too large extension");
+                }
+            }
+        }
+        (Ttl::Extend(_), None) => {
+            return Response::builder()
+                .status(StatusCode::CONFLICT)
+                .body("The value doesn't expire, its expiration can't be extended".into())
+                .context("Could not build conflict response");
+        }
+    };
+    // A sliding expiration keeps sliding, with the duration until the new deadline.
+    let sliding = entry
+        .sliding
+        .as_ref()
+        .and(deadline)
+        .map(|deadline| deadline.saturating_duration_since(Instant::now()));
+    if let Some(aof) = &state.aof {
+        let record = aof::expiration_record(&key, entry, deadline, sliding);
+        aof.append(record).await?;
+    }
+    state.set_deadline(&mut write_kv, &key, deadline, sliding);
+    with_expiration(Response::builder(), deadline)
+        .status(StatusCode::NO_CONTENT)
+        .header("X-memoryhttpd-action", "ttl")
+        .body(Body::empty())
+        .context("Could not build response")
+}
+
+/// Delete all the keys starting with a prefix, at once.
+async fn delete_prefix(state: State, prefix: String) -> Result<Response<Body>> {
+    // Otherwise "/foo" would also delete "/foobar".
+    if !prefix.ends_with('/') {
+        return Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body("The path of a recursive deletion must end with a slash".into())
+            .context("Could not build bad request for recursive deletion");
+    }
+    let mut write_kv = state.kv.write().await;
+    let now = Instant::now();
+    let mut deleted = 0;
+    let keys: Vec<String> = write_kv
+        .range(prefix.clone()..)
+        .take_while(|(key, _)| key.starts_with(&prefix))
+        .map(|(key, entry)| {
+            // Expired values are removed too, but they were already gone for the clients.
+            if !entry.expired(now) {
+                deleted += 1;
+            }
+            key.clone()
+        })
+        .collect();
+    if let (Some(aof), false) = (&state.aof, keys.is_empty()) {
+        let records = keys
+            .iter()
+            .flat_map(|key| aof::delete_record(key))
+            .collect();
+        aof.append(records).await?;
+    }
+    for key in &keys {
+        write_kv.remove(key);
+    }
+    log::debug!("Deleted {deleted} keys under \"{prefix}\"");
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("X-memoryhttpd-deleted", deleted)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(serde_json::json!({ "deleted": deleted }).to_string().into())
+        .context("Could not build response")
+}
+
+/// Parse a header containing a number of milliseconds, if it's present.
+fn millis_header(req: &Request<Body>, name: &str) -> Option<Result<u64, String>> {
+    req.headers().get(name).map(|h| {
+        h.to_str()
+            .ok()
+            .and_then(|s| s.parse::<u64>().ok())
+            .ok_or_else(|| format!("{name} is not a valid number"))
+    })
+}
+
+/// Whether a flag, like "?recursive", is present in the query string.
+fn has_flag(req: &Request<Body>, flag: &str) -> bool {
+    let query = req.uri().query().unwrap_or_default();
+    form_urlencoded::parse(query.as_bytes()).any(|(name, _)| name == flag)
+}
+
+async fn handler(state: State, mode: Mode, mut req: Request<Body>) -> Result<Response<Body>> {
+    // HTTP/2 requests carry the host in the ":authority" pseudo-header instead of "Host".
     let host = req
         .headers()
         .get(header::HOST)
-        .map(|v| v.to_str())
+        // Internationalized hosts may be sent in UTF-8, they are converted by hosts::normalize().
+        .map(|v| std::str::from_utf8(v.as_bytes()))
         .transpose()
         .context("Could not read host header")?
+        .or_else(|| req.uri().authority().map(|a| a.as_str()))
         .unwrap_or("localhost");
     let method = req.method().as_str();
     let path = req.uri().path();
@@ -116,86 +534,231 @@ async fn handler(state: State, mut req: Request<Body>) -> Result<Response<Body>>
             .context("Could not build bad request response for missing leading slash");
     }
 
+    let requested_host = match hosts::normalize(host) {
+        Some(host) => host,
+        None => {
+            return Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body("Invalid host".into())
+                .context("Could not build bad request response for invalid host")
+        }
+    };
+    // The values of aliases are stored under the host of their namespace.
+    let host = match state.hosts.namespace(&requested_host) {
+        Some(namespace) => namespace,
+        None => {
+            return Response::builder()
+                .status(StatusCode::MISDIRECTED_REQUEST)
+                .body(format!("Unknown host {requested_host}").into())
+                .context("Could not build misdirected request response")
+        }
+    };
+    let host = host.as_str();
+
+    let read = [Method::GET, Method::HEAD, Method::OPTIONS].contains(req.method());
+    if !read && mode == Mode::ReadOnly {
+        return Response::builder()
+            .status(StatusCode::METHOD_NOT_ALLOWED)
+            .header(header::ALLOW, allowed_methods(mode))
+            .body(Body::empty())
+            .context("Could not build response method not allowed");
+    }
+    let recursive = req.method() == Method::DELETE && has_flag(&req, "recursive");
+    if recursive && mode != Mode::Admin {
+        return Response::builder()
+            .status(StatusCode::FORBIDDEN)
+            .body("Recursive deletion is only allowed on admin addresses".into())
+            .context("Could not build forbidden response");
+    }
+
+    // Listings reveal the keys, which can't be guessed otherwise. They need a token, unless they
+    // are made on an admin address.
+    let listing =
+        req.method() == Method::GET && (has_flag(&req, "list") || has_flag(&req, "usage"));
+    let token_required = listing && mode != Mode::Admin;
+    if token_required && state.auth.is_none() {
+        return Response::builder()
+            .status(StatusCode::FORBIDDEN)
+            .body("Listings are only allowed on admin addresses, or with a token".into())
+            .context("Could not build forbidden response for listing");
+    }
+
+    let signature = state
+        .presign
+        .as_ref()
+        .map(|p| {
+            p.check(
+                req.method(),
+                &requested_host,
+                path,
+                req.uri().query().unwrap_or_default(),
+            )
+        })
+        .unwrap_or(Signature::Missing);
+    if let Signature::Invalid(reason) = signature {
+        return Response::builder()
+            .status(StatusCode::FORBIDDEN)
+            .body(reason.into())
+            .context("Could not build forbidden response for invalid signature");
+    }
+
+    // A valid presigned URL stands in for a bearer token. Both are scoped to the host requested,
+    // not to the namespace it's stored in.
+    let auth = state
+        .auth
+        .as_ref()
+        .filter(|_| signature == Signature::Missing);
+    if let Some(auth) = auth {
+        let decision = if token_required {
+            auth.authenticate(req.headers(), req.method(), &requested_host, path)
+        } else {
+            auth.check(req.headers(), req.method(), &requested_host, path)
+        };
+        let (status, challenge) = match decision {
+            Decision::Allowed => (None, ""),
+            Decision::Unauthorized => (Some(StatusCode::UNAUTHORIZED), ""),
+            Decision::Forbidden => (
+                Some(StatusCode::FORBIDDEN),
+                ", error=\"insufficient_scope\"",
+            ),
+        };
+        if let Some(status) = status {
+            return Response::builder()
+                .status(status)
+                .header(
+                    header::WWW_AUTHENTICATE,
+                    format!("Bearer realm=\"memoryhttpd\"{challenge}"),
+                )
+                .body(Body::empty())
+                .context("Could not build authentication failure response");
+        }
+    }
+
     let key: String = host.chars().chain(req.uri().path().chars()).collect();
 
+    let preconditions = Preconditions::from_headers(req.headers());
+
     let method = req.method();
     if method == Method::GET {
-        get(state, key).await.context("Could not get value")
+        if has_flag(&req, "usage") {
+            return quota::usage(state, host)
+                .await
+                .context("Could not report usage");
+        }
+        if let Some(params) = req.uri().query().and_then(listing::Params::from_query) {
+            return match params {
+                Ok(params) => listing::list(state, host, path, params)
+                    .await
+                    .context("Could not list keys"),
+                Err(err) => Response::builder()
+                    .status(StatusCode::BAD_REQUEST)
+                    .body(err.into())
+                    .context("Could not build bad request for bad listing parameters"),
+            };
+        }
+        get(state, key, preconditions)
+            .await
+            .context("Could not get value")
     } else if method == Method::PUT {
-        let expire = req.headers().get("x-expire-ms").map(|h| {
-            h.to_str()
-                .map_err(|_| "x-expire-ms is not ascii")
-                .and_then(|s| {
-                    s.parse::<u64>()
-                        .map_err(|_| "x-expire-ms is not a valid number")
-                })
-        });
-        let expire = match expire {
-            None => state.default_expiration,
-            Some(Ok(exp)) => exp,
-            Some(Err(err)) => {
+        let expire = match ttl::from_headers(req.headers(), state.cache_control_expiration) {
+            Ok(expire) => expire.unwrap_or(state.default_expiration),
+            Err(err) => {
                 return Response::builder()
                     .status(StatusCode::BAD_REQUEST)
                     .body(err.into())
                     .context("Could not build bad request for bad expiration header")
             }
         };
-        let content = body::to_bytes(req.body_mut())
+        let sliding = match ttl::sliding(req.headers()) {
+            Ok(true) if expire == Expire::Never => {
+                return Response::builder()
+                    .status(StatusCode::BAD_REQUEST)
+                    .body("A sliding expiration needs an expiration".into())
+                    .context("Could not build bad request for sliding expiration")
+            }
+            Ok(sliding) => sliding,
+            Err(err) => {
+                return Response::builder()
+                    .status(StatusCode::BAD_REQUEST)
+                    .body(err.into())
+                    .context("Could not build bad request for bad expiration mode")
+            }
+        };
+        let headers = state.stored_headers(req.headers());
+        let content =
+            match ingest::read(req.body_mut(), state.max_body_size, state.body_timeout).await {
+                Ok(content) => content,
+                Err(err) => {
+                    let status = err.status().context(err.message())?;
+                    log::info!(
+                        "Refusing body of \"{key}\": {message}",
+                        message = err.message()
+                    );
+                    // The rest of the body isn't read, the connection can't be reused.
+                    return Response::builder()
+                        .status(status)
+                        .header(header::CONNECTION, "close")
+                        .body(err.message().into())
+                        .context("Could not build response for refused body");
+                }
+            };
+        set(
+            state,
+            key,
+            &content,
+            headers,
+            expire,
+            sliding,
+            preconditions,
+        )
+        .await
+        .context("Could not set value")
+    } else if method == Method::PATCH {
+        let ttl = match (
+            ttl::from_headers(req.headers(), state.cache_control_expiration).transpose(),
+            millis_header(&req, "x-extend-ms"),
+        ) {
+            (Some(Ok(expire)), None) => Ttl::Set(expire),
+            (None, Some(Ok(ms))) => Ttl::Extend(ms),
+            (Some(Err(err)), _) | (_, Some(Err(err))) => {
+                return Response::builder()
+                    .status(StatusCode::BAD_REQUEST)
+                    .body(err.into())
+                    .context("Could not build bad request for bad expiration header")
+            }
+            _ => {
+                return Response::builder()
+                    .status(StatusCode::BAD_REQUEST)
+                    .body("PATCH needs either an expiration or X-Extend-ms".into())
+                    .context("Could not build bad request for missing expiration header")
+            }
+        };
+        patch(state, key, ttl, preconditions)
             .await
-            .context("Could not read body")?;
-        set(state, key, content.as_ref(), expire)
+            .context("Could not change expiration")
+    } else if recursive {
+        delete_prefix(state, key)
             .await
-            .context("Could not set value")
+            .context("Could not delete values")
     } else if method == Method::DELETE {
-        delete(state, key).await.context("Could not delete value")
+        delete(state, key, preconditions)
+            .await
+            .context("Could not delete value")
+    } else if method == Method::HEAD {
+        head(state, key, preconditions)
+            .await
+            .context("Could not get value")
+    } else if method == Method::OPTIONS {
+        options(mode).context("Could not get options")
     } else {
         Response::builder()
             .status(StatusCode::METHOD_NOT_ALLOWED)
+            .header(header::ALLOW, allowed_methods(mode))
             .body(Body::empty())
             .context("Could not build response method not allowed")
     }
 }
 
-impl Ord for Expiration {
-    fn cmp(&self, other: &Self) -> Ordering {
-        self.deadline
-            .cmp(&other.deadline)
-            .reverse()
-            .then_with(|| self.key.cmp(&other.key))
-    }
-}
-
-impl PartialOrd for Expiration {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.cmp(other))
-    }
-}
-
-async fn expiring(
-    kv: Arc<RwLock<HashMap<String, Vec<u8>>>>,
-    mut requests: mpsc::Receiver<Expiration>,
-) {
-    let mut heap: BinaryHeap<Expiration> = Default::default();
-    loop {
-        let next_deadline = heap
-            .peek()
-            .map(|e| e.deadline)
-            .unwrap_or_else(|| Instant::now() + Duration::from_secs(24 * 60 * 60));
-        tokio::select! {
-            Some(exp) = requests.recv() => heap.push(exp),
-            _ = sleep_until(next_deadline) => {
-                if let Some(exp) = heap.peek() {
-                    log::debug!("Expiration of key \"{key}\"", key=&exp.key);
-                    let mut write_kv = kv.write().await;
-                    write_kv.remove(&exp.key);
-                    heap.pop();
-                }
-            }
-            else => break,
-        }
-    }
-}
-
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
@@ -211,13 +774,145 @@ struct Args {
     #[arg(long, default_value_t = 0)]
     default_expiration: u64,
 
-    /// Address to bind on. It needs to also contain the hostname, use
-    /// 0.0.0.0 to listen on all addresses. (e.g. "0.0.0.0:3000")
-    address: SocketAddr,
+    /// Also take the expiration of values from the max-age of their Cache-Control header, or from
+    /// their Expires header, when there is no X-Expire-* header.
+    #[arg(long, default_value_t = false)]
+    cache_control_expiration: bool,
+
+    /// Additional X-Meta-* header to store along with the values and to return on GET. (Can be
+    /// repeated)
+    #[arg(long = "meta-header", value_parser = parse_meta_header)]
+    meta_headers: Vec<HeaderName>,
+
+    /// File where the content of the store is saved, and restored from at startup.
+    #[arg(long)]
+    snapshot_path: Option<PathBuf>,
+
+    /// Interval between two snapshots, in seconds (Zero means only on SIGTERM).
+    #[arg(long, default_value_t = 60)]
+    snapshot_interval: u64,
+
+    /// File where all the modifications are logged, and replayed from at startup.
+    #[arg(long)]
+    aof_path: Option<PathBuf>,
+
+    /// When to flush the modifications logged to the disk.
+    #[arg(long, value_enum, default_value_t = Fsync::EverySecond)]
+    aof_fsync: Fsync,
+
+    /// Certificate chain to serve HTTPS with, in PEM format.
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// Private key of the certificate, in PEM format.
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// Directory containing a certificate per host, named "<hostname>.crt" and "<hostname>.key".
+    /// (The certificate from --tls-cert is used for the other hosts)
+    #[arg(long, requires = "tls_cert")]
+    tls_cert_dir: Option<PathBuf>,
+
+    /// Host allowed to be served, others are refused with 421 Misdirected Request. Can be
+    /// "*.domain" for all the subdomains, and followed by "=NAMESPACE" to store the values of
+    /// several hosts together. (e.g. "example.com", "www.example.com=example.com"). All hosts are
+    /// allowed when none is given.
+    #[arg(long = "host")]
+    hosts: Vec<HostRule>,
+
+    /// Maximum size of a request body, in bytes, with an optional K, M or G suffix.
+    #[arg(long, value_parser = parse_size, default_value = "16M")]
+    max_body_size: usize,
+
+    /// Time given to the clients to send a request body, in seconds.
+    #[arg(long, default_value_t = 30)]
+    body_timeout: u64,
+
+    /// Maximum memory used by the keys and the values, in bytes, with an optional K, M or G suffix.
+    #[arg(long, value_parser = parse_size)]
+    max_memory: Option<usize>,
+
+    /// What to do when --max-memory is reached.
+    #[arg(long, value_enum, default_value_t = Eviction::Reject, requires = "max_memory")]
+    eviction: Eviction,
+
+    /// Limits of a host, or of all the hosts when HOST is omitted, as "[HOST=]LIMIT,...". LIMIT is
+    /// "bytes=SIZE", "keys=COUNT" or "value-size=SIZE". (e.g. "bytes=10M,value-size=1M" or
+    /// "example.com=keys=1000")
+    #[arg(long = "quota")]
+    quotas: Vec<QuotaRule>,
+
+    /// File of the bearer tokens allowed to modify values, with the hosts, path prefixes and
+    /// methods allowed for each token. (See the README for the format)
+    #[arg(long)]
+    auth_tokens: Option<PathBuf>,
+
+    /// Also require a token to read values.
+    #[arg(long, default_value_t = false, requires = "auth_tokens")]
+    auth_reads: bool,
+
+    /// File of the secrets used to sign presigned URLs, one "KEY_ID SECRET" per line.
+    #[arg(long)]
+    presign_keys: Option<PathBuf>,
+
+    /// Time given to the open connections to finish when shutting down, in seconds.
+    #[arg(long, default_value_t = 30)]
+    shutdown_timeout: u64,
+
+    /// Maximum number of concurrent HTTP/2 streams per connection.
+    #[arg(long)]
+    http2_max_concurrent_streams: Option<u32>,
+
+    /// Initial HTTP/2 flow control window of each stream, in bytes.
+    #[arg(long)]
+    http2_stream_window_size: Option<u32>,
+
+    /// Initial HTTP/2 flow control window of each connection, in bytes.
+    #[arg(long)]
+    http2_connection_window_size: Option<u32>,
+
+    /// Mode of the Unix domain sockets, in octal. (e.g. "660")
+    #[arg(long, value_parser = listener::parse_mode)]
+    unix_socket_mode: Option<u32>,
+
+    /// Owner of the Unix domain sockets, as "user", "user:group" or ":group".
+    #[arg(long)]
+    unix_socket_owner: Option<Owner>,
+
+    /// Addresses to listen on, either "tcp:ADDRESS", "unix:PATH" or "systemd:NAME" for a socket
+    /// passed by systemd, prefixed by "tls:" to serve HTTPS. A TCP address needs to also contain
+    /// the hostname, use 0.0.0.0 to listen on all addresses. The address can be prefixed by
+    /// "read-only=", "read-write=" or "admin=" (the default) to restrict what clients can do.
+    /// (e.g. "0.0.0.0:3000" or "read-only=tls:0.0.0.0:443")
+    #[arg(required = true)]
+    addresses: Vec<Address>,
+}
+
+/// Parse a size in bytes, with an optional K, M or G suffix (powers of 1024).
+fn parse_size(size: &str) -> Result<usize> {
+    let (number, multiplier) = match size.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&size[..i], 1 << 10),
+        Some((i, 'M' | 'm')) => (&size[..i], 1 << 20),
+        Some((i, 'G' | 'g')) => (&size[..i], 1 << 30),
+        _ => (size, 1),
+    };
+    let number: usize = number.parse().context("Invalid size")?;
+    number.checked_mul(multiplier).context("Size too large")
+}
+
+fn parse_meta_header(name: &str) -> Result<HeaderName> {
+    let name = HeaderName::try_from(name).context("Invalid header name")?;
+    if !name.as_str().starts_with("x-meta-") {
+        anyhow::bail!("Header name must start with \"X-Meta-\"");
+    }
+    Ok(name)
 }
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<()> {
+    // The environment can only be changed safely while there is no other thread.
+    let mut inherited = systemd::listen_fds().context("Could not get sockets from systemd")?;
+
     let args = Args::parse();
 
     SimpleLogger::new()
@@ -226,24 +921,289 @@ async fn main() -> Result<()> {
         .init()
         .context("Could not initialize logging")?;
 
-    let (expirations_send, expirations_recv) = mpsc::channel(25);
+    let drain = Drain::default();
+
+    let aof = args
+        .aof_path
+        .map(|path| Aof::open(path, args.aof_fsync))
+        .transpose()?
+        .map(Arc::new);
+
+    let auth = args
+        .auth_tokens
+        .map(|path| Auth::load(&path, args.auth_reads))
+        .transpose()
+        .context("Could not load authentication tokens")?
+        .map(Arc::new);
+
+    let presign = args
+        .presign_keys
+        .map(|path| Presign::load(&path))
+        .transpose()
+        .context("Could not load presigned URL keys")?
+        .map(Arc::new);
 
     let state = State {
         kv: Default::default(),
-        expirations: expirations_send,
-        default_expiration: args.default_expiration,
+        generations: Default::default(),
+        expirations: Default::default(),
+        default_expiration: match args.default_expiration {
+            0 => Expire::Never,
+            seconds => Expire::In(Duration::from_secs(seconds)),
+        },
+        cache_control_expiration: args.cache_control_expiration,
+        meta_headers: Arc::new(args.meta_headers),
+        aof,
+        auth,
+        presign,
+        hosts: Arc::new(Hosts::new(args.hosts)),
+        memory_limit: args.max_memory.map(|max| MemoryLimit {
+            max,
+            eviction: args.eviction,
+        }),
+        quotas: Arc::new(Quotas::new(args.quotas)),
+        max_body_size: args.max_body_size,
+        body_timeout: Duration::from_secs(args.body_timeout),
     };
 
-    task::spawn(expiring(state.kv.clone(), expirations_recv));
+    task::spawn(expiry::expiring(
+        state.kv.clone(),
+        state.aof.clone(),
+        state.expirations.clone(),
+        drain.shutdown(),
+    ));
 
-    let make_svc = make_service_fn(|_conn| {
-        let state = state.clone();
-        async move { Ok::<_, Infallible>(service_fn(move |req| handler(state.clone(), req))) }
-    });
+    let mut periodic_snapshot = None;
+    if let Some(path) = &args.snapshot_path {
+        snapshot::restore(&state, path)
+            .await
+            .context("Could not restore snapshot")?;
+        if args.snapshot_interval > 0 {
+            let interval = Duration::from_secs(args.snapshot_interval);
+            periodic_snapshot = Some(task::spawn(snapshot::periodic(
+                state.clone(),
+                path.clone(),
+                interval,
+                drain.shutdown(),
+            )));
+        }
+    }
 
-    Server::bind(&args.address)
-        .serve(make_svc)
-        .await
-        .context("Server error")?;
+    if let Some(aof) = &state.aof {
+        aof.replay(&state)
+            .await
+            .context("Could not replay append-only log")?;
+        // The log is compacted right away, so that it always starts with the whole store.
+        aof.compact(&*state.kv.read().await).await?;
+        task::spawn(aof::background(state.clone(), aof.clone()));
+    }
+
+    let tls = match (args.tls_cert, args.tls_key) {
+        (Some(cert), Some(key)) => {
+            let files = TlsFiles {
+                cert,
+                key,
+                cert_dir: args.tls_cert_dir,
+            };
+            let resolver = Arc::new(Resolver::new(files).context("Could not load certificates")?);
+            task::spawn(tls::reload(resolver.clone()));
+            Some(tls::acceptor(resolver))
+        }
+        _ => None,
+    };
+    let uses_tls = args.addresses.iter().any(|a| a.tls);
+    if uses_tls != tls.is_some() {
+        anyhow::bail!("--tls-cert and --tls-key are needed if and only if there is a tls: address");
+    }
+
+    let mut http = Http::new();
+    http.http2_max_concurrent_streams(args.http2_max_concurrent_streams)
+        .http2_initial_stream_window_size(args.http2_stream_window_size)
+        .http2_initial_connection_window_size(args.http2_connection_window_size);
+
+    let permissions = UnixPermissions {
+        mode: args.unix_socket_mode,
+        owner: args.unix_socket_owner,
+    };
+    let mut servers = JoinSet::new();
+    for address in &args.addresses {
+        let tls = tls.clone().filter(|_| address.tls);
+        for listener in listener::bind(address, &permissions, &mut inherited).await? {
+            servers.spawn(server::serve(
+                listener,
+                address.mode,
+                tls.clone(),
+                http.clone(),
+                state.clone(),
+                drain.shutdown(),
+            ));
+        }
+        log::info!("Listening on {address}");
+    }
+    for (name, _) in inherited {
+        log::warn!("Ignoring socket \"{name}\" passed by systemd, it's not in the addresses");
+    }
+
+    systemd::notify("READY=1");
+    task::spawn(systemd::watchdog());
+
+    let mut sigterm = signal(SignalKind::terminate()).context("Could not handle SIGTERM")?;
+    let mut sigint = signal(SignalKind::interrupt()).context("Could not handle SIGINT")?;
+    let result = tokio::select! {
+        Some(res) = servers.join_next() => res.context("Server panicked").and_then(|r| r),
+        _ = sigterm.recv() => {
+            log::info!("Received SIGTERM, shutting down");
+            Ok(())
+        }
+        _ = sigint.recv() => {
+            log::info!("Received SIGINT, shutting down");
+            Ok(())
+        }
+    };
+    systemd::notify("STOPPING=1");
+
+    let timeout = Duration::from_secs(args.shutdown_timeout);
+    let drained = drain.drain(timeout).await;
+    if !drained {
+        log::warn!("Some connections were still open after {timeout:?}, closing them");
+    }
+    servers.shutdown().await;
+    args.addresses.iter().for_each(listener::cleanup);
+
+    // The periodic snapshot must not be written at the same time as the last one.
+    if let Some(periodic_snapshot) = periodic_snapshot {
+        if let Err(err) = periodic_snapshot.await {
+            log::error!("Periodic snapshot task failed: {err}");
+        }
+    }
+    if let Some(path) = &args.snapshot_path {
+        snapshot::save(&state, path)
+            .await
+            .context("Could not save snapshot")?;
+    }
+    if let Some(aof) = &state.aof {
+        aof.sync().await?;
+    }
+    result?;
+    anyhow::ensure!(
+        drained,
+        "Connections were closed before their requests finished"
+    );
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::time;
+
+    const KEY: &str = "example.com/key";
+
+    pub(crate) fn state() -> State {
+        State {
+            kv: Default::default(),
+            generations: Default::default(),
+            expirations: Default::default(),
+            default_expiration: Expire::Never,
+            cache_control_expiration: false,
+            meta_headers: Default::default(),
+            aof: None,
+            auth: None,
+            presign: None,
+            hosts: Arc::new(Hosts::new(Vec::new())),
+            memory_limit: None,
+            quotas: Default::default(),
+            max_body_size: 1024,
+            body_timeout: Duration::from_secs(1),
+        }
+    }
+
+    pub(crate) fn entry(state: &State, value: &[u8], deadline: Option<Instant>) -> Entry {
+        Entry {
+            value: value.to_vec(),
+            generation: state.next_generation(),
+            headers: HeaderMap::new(),
+            etag: conditional::etag(value),
+            modified: SystemTime::now(),
+            deadline,
+            sliding: None,
+            usage: Default::default(),
+        }
+    }
+
+    async fn put(state: &State, expire: Expire, sliding: bool) {
+        let preconditions = Preconditions::from_headers(&HeaderMap::new());
+        let headers = HeaderMap::new();
+        let response = set(
+            state.clone(),
+            KEY.to_owned(),
+            b"value",
+            headers,
+            expire,
+            sliding,
+            preconditions,
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    async fn status(state: &State) -> StatusCode {
+        let preconditions = Preconditions::from_headers(&HeaderMap::new());
+        get(state.clone(), KEY.to_owned(), preconditions)
+            .await
+            .unwrap()
+            .status()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn expired_value_not_served_before_removal() {
+        // The expiration task isn't running, as if it was lagging behind.
+        let state = state();
+        put(&state, Expire::In(Duration::from_secs(10)), false).await;
+        time::advance(Duration::from_millis(9999)).await;
+        assert_eq!(status(&state).await, StatusCode::OK);
+        time::advance(Duration::from_millis(1)).await;
+        assert_eq!(status(&state).await, StatusCode::NOT_FOUND);
+        assert!(state.kv.read().await.contains_key(KEY));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn expired_sliding_value_not_revived_by_read() {
+        let state = state();
+        put(&state, Expire::In(Duration::from_secs(10)), true).await;
+        time::advance(Duration::from_secs(8)).await;
+        assert_eq!(status(&state).await, StatusCode::OK);
+        time::advance(Duration::from_secs(8)).await;
+        assert_eq!(status(&state).await, StatusCode::OK);
+        time::advance(Duration::from_secs(10)).await;
+        assert_eq!(status(&state).await, StatusCode::NOT_FOUND);
+        assert_eq!(status(&state).await, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn value_without_expiration_kept() {
+        let state = state();
+        put(&state, Expire::Never, false).await;
+        time::advance(Duration::from_secs(365 * 24 * 60 * 60)).await;
+        assert_eq!(status(&state).await, StatusCode::OK);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn expired_value_removed_in_background() {
+        let state = state();
+        let drain = Drain::default();
+        task::spawn(expiry::expiring(
+            state.kv.clone(),
+            None,
+            state.expirations.clone(),
+            drain.shutdown(),
+        ));
+        put(&state, Expire::In(Duration::from_secs(10)), false).await;
+        time::sleep(Duration::from_secs(9)).await;
+        assert!(state.kv.read().await.contains_key(KEY));
+        time::sleep(Duration::from_secs(2)).await;
+        assert!(!state.kv.read().await.contains_key(KEY));
+    }
+}
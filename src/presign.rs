@@ -0,0 +1,235 @@
+// Copyright 2023, Antoine Catton
+//
+// Permission to use, copy, modify, and/or distribute this software for any purpose with or without
+// fee is hereby granted, provided that the above copyright notice and this permission notice
+// appear in all copies.
+//
+// THE SOFTWARE IS PROVIDED “AS IS” AND THE AUTHOR DISCLAIMS ALL WARRANTIES WITH REGARD TO THIS
+// SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE
+// AUTHOR BE LIABLE FOR ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+// WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN ACTION OF CONTRACT,
+// NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF OR IN CONNECTION WITH THE USE OR PERFORMANCE
+// OF THIS SOFTWARE.
+
+//! Presigned URLs, allowing a single method on a single key until an expiration time.
+//!
+//! The URL carries the key id, the expiration (in milliseconds since the Unix epoch), the method
+//! and the signature in the query string:
+//!
+//! ```text
+//! /path?X-Key-Id=ci&X-Expires-At=1700000000000&X-Method=PUT&X-Signature=...
+//! ```
+//!
+//! The signature is the hex encoded HMAC-SHA256 of "METHOD\nhost\npath\nexpires", with the host
+//! requested, normalized, like for the bearer tokens. No other query parameter is accepted along
+//! with the signature. The keys file contains one "KEY_ID SECRET" per line.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use hmac::{Hmac, Mac};
+use hyper::Method;
+use sha2::Sha256;
+
+use crate::auth::{host_without_port, tokens_match};
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Signature {
+    /// The URL isn't signed.
+    Missing,
+    Valid,
+    Invalid(&'static str),
+}
+
+#[derive(Debug)]
+pub struct Presign {
+    secrets: HashMap<String, Vec<u8>>,
+}
+
+impl Presign {
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Could not read {path}", path = path.display()))?;
+        let mut secrets = HashMap::new();
+        for (number, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (id, secret) = line
+                .split_once(char::is_whitespace)
+                .map(|(id, secret)| (id, secret.trim()))
+                .filter(|(_, secret)| !secret.is_empty())
+                .with_context(|| {
+                    format!(
+                        "Expected a key id and a secret on {path}:{line}",
+                        path = path.display(),
+                        line = number + 1
+                    )
+                })?;
+            secrets.insert(id.to_owned(), secret.as_bytes().to_vec());
+        }
+        Ok(Presign { secrets })
+    }
+
+    pub fn check(&self, method: &Method, host: &str, path: &str, query: &str) -> Signature {
+        let params: Vec<_> = form_urlencoded::parse(query.as_bytes()).collect();
+        let others = params.iter().any(|(name, _)| !name.starts_with("X-"));
+        let mut params: HashMap<_, _> = params.into_iter().collect();
+        let signature = match params.remove("X-Signature") {
+            Some(signature) => signature,
+            None => return Signature::Missing,
+        };
+        // Flags like "recursive" or "list" aren't signed, they would turn the URL into another
+        // operation than the one on a single key.
+        if others {
+            return Signature::Invalid("Presigned URLs can't have other query parameters");
+        }
+        let (id, expires, signed_method) = match (
+            params.remove("X-Key-Id"),
+            params.remove("X-Expires-At"),
+            params.remove("X-Method"),
+        ) {
+            (Some(id), Some(expires), Some(method)) => (id, expires, method),
+            _ => return Signature::Invalid("Missing X-Key-Id, X-Expires-At or X-Method"),
+        };
+        let secret = match self.secrets.get(id.as_ref()) {
+            Some(secret) => secret,
+            None => return Signature::Invalid("Unknown key id"),
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default();
+        match expires.parse::<u64>() {
+            Ok(expires) if expires >= now => {}
+            Ok(_) => return Signature::Invalid("Expired signature"),
+            Err(_) => return Signature::Invalid("Invalid expiration"),
+        }
+        // A URL signed for GET can also be used for HEAD.
+        let method_allowed =
+            signed_method == method.as_str() || (signed_method == "GET" && method == Method::HEAD);
+        if !method_allowed {
+            return Signature::Invalid("Method not allowed by the signature");
+        }
+        let expected = sign(secret, &signed_method, host, path, &expires);
+        if tokens_match(&expected, &signature.to_ascii_lowercase()) {
+            Signature::Valid
+        } else {
+            Signature::Invalid("Invalid signature")
+        }
+    }
+}
+
+fn sign(secret: &[u8], method: &str, host: &str, path: &str, expires: &str) -> String {
+    let host = host_without_port(host).to_ascii_lowercase();
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts keys of any size");
+    mac.update(format!("{method}\n{host}\n{path}\n{expires}").as_bytes());
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = b"secret";
+
+    fn presign() -> Presign {
+        Presign {
+            secrets: HashMap::from([("ci".to_owned(), SECRET.to_vec())]),
+        }
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64
+    }
+
+    /// The query string of a URL signed for a method on example.com/key.
+    fn query(method: &str, expires: u64) -> String {
+        let signature = sign(SECRET, method, "example.com", "/key", &expires.to_string());
+        format!("X-Key-Id=ci&X-Expires-At={expires}&X-Method={method}&X-Signature={signature}")
+    }
+
+    fn check(method: Method, query: &str) -> Signature {
+        presign().check(&method, "example.com", "/key", query)
+    }
+
+    #[test]
+    fn valid() {
+        let query = query("PUT", now() + 60_000);
+        assert_eq!(check(Method::PUT, &query), Signature::Valid);
+        let signed_host = presign().check(&Method::PUT, "Example.COM:8080", "/key", &query);
+        assert_eq!(signed_host, Signature::Valid);
+    }
+
+    #[test]
+    fn get_allows_head() {
+        let query = query("GET", now() + 60_000);
+        assert_eq!(check(Method::HEAD, &query), Signature::Valid);
+    }
+
+    #[test]
+    fn missing() {
+        assert_eq!(check(Method::GET, ""), Signature::Missing);
+        assert_eq!(check(Method::GET, "list"), Signature::Missing);
+    }
+
+    #[test]
+    fn bad_signature() {
+        let query = query("PUT", now() + 60_000).replace("X-Signature=", "X-Signature=0");
+        assert_eq!(
+            check(Method::PUT, &query),
+            Signature::Invalid("Invalid signature")
+        );
+        let other_path = presign().check(&Method::PUT, "example.com", "/other", &query);
+        assert_eq!(other_path, Signature::Invalid("Invalid signature"));
+    }
+
+    #[test]
+    fn unknown_key_id() {
+        let query = query("PUT", now() + 60_000).replace("X-Key-Id=ci", "X-Key-Id=cd");
+        assert_eq!(
+            check(Method::PUT, &query),
+            Signature::Invalid("Unknown key id")
+        );
+    }
+
+    #[test]
+    fn expired() {
+        let query = query("PUT", now() - 1);
+        assert_eq!(
+            check(Method::PUT, &query),
+            Signature::Invalid("Expired signature")
+        );
+    }
+
+    #[test]
+    fn wrong_method() {
+        let query = query("GET", now() + 60_000);
+        assert_eq!(
+            check(Method::DELETE, &query),
+            Signature::Invalid("Method not allowed by the signature")
+        );
+    }
+
+    #[test]
+    fn extra_query_flags() {
+        for flag in ["recursive", "list", "usage", "limit=10"] {
+            let query = format!("{query}&{flag}", query = query("DELETE", now() + 60_000));
+            assert_eq!(
+                check(Method::DELETE, &query),
+                Signature::Invalid("Presigned URLs can't have other query parameters")
+            );
+        }
+    }
+}
@@ -0,0 +1,278 @@
+// Copyright 2023, Antoine Catton
+//
+// Permission to use, copy, modify, and/or distribute this software for any purpose with or without
+// fee is hereby granted, provided that the above copyright notice and this permission notice
+// appear in all copies.
+//
+// THE SOFTWARE IS PROVIDED “AS IS” AND THE AUTHOR DISCLAIMS ALL WARRANTIES WITH REGARD TO THIS
+// SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE
+// AUTHOR BE LIABLE FOR ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+// WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN ACTION OF CONTRACT,
+// NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF OR IN CONNECTION WITH THE USE OR PERFORMANCE
+// OF THIS SOFTWARE.
+
+//! Per-host quotas on the bytes stored, the number of keys and the size of a single value.
+
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+use http::header;
+use hyper::{Body, Response, StatusCode};
+use serde_json::json;
+
+use crate::store::HostUsage;
+use crate::{hosts, parse_size, State};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    pub bytes: Option<usize>,
+    pub keys: Option<usize>,
+    pub value_size: Option<usize>,
+}
+
+impl Limits {
+    /// The limits set here, and the other ones from a fallback.
+    fn or(self, fallback: Limits) -> Limits {
+        Limits {
+            bytes: self.bytes.or(fallback.bytes),
+            keys: self.keys.or(fallback.keys),
+            value_size: self.value_size.or(fallback.value_size),
+        }
+    }
+}
+
+/// Limits for a host, or for all of them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuotaRule {
+    host: Option<String>,
+    limits: Limits,
+}
+
+impl FromStr for QuotaRule {
+    type Err = String;
+
+    /// Parse "[HOST=]LIMIT,...", where LIMIT is "bytes=SIZE", "keys=COUNT" or "value-size=SIZE".
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (host, limits) = match s
+            .split_once(',')
+            .map_or(s, |(first, _)| first)
+            .split_once('=')
+        {
+            Some((name, _)) if !["bytes", "keys", "value-size"].contains(&name) => {
+                let host = hosts::normalize(name).ok_or(format!("Invalid host \"{name}\""))?;
+                (Some(host), &s[name.len() + 1..])
+            }
+            _ => (None, s),
+        };
+        let mut parsed = Limits::default();
+        for limit in limits.split(',') {
+            let (name, value) = limit
+                .split_once('=')
+                .ok_or(format!("Invalid limit \"{limit}\""))?;
+            let invalid = || format!("Invalid value for {name}: \"{value}\"");
+            match name {
+                "bytes" => parsed.bytes = Some(parse_size(value).map_err(|_| invalid())?),
+                "keys" => parsed.keys = Some(value.parse().map_err(|_| invalid())?),
+                "value-size" => parsed.value_size = Some(parse_size(value).map_err(|_| invalid())?),
+                name => return Err(format!("Unknown limit \"{name}\"")),
+            }
+        }
+        Ok(QuotaRule {
+            host,
+            limits: parsed,
+        })
+    }
+}
+
+/// A quota which would be exceeded by a write.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Exceeded {
+    ValueSize(usize),
+    Bytes(usize),
+    Keys(usize),
+}
+
+impl Exceeded {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Exceeded::ValueSize(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            Exceeded::Bytes(_) | Exceeded::Keys(_) => StatusCode::INSUFFICIENT_STORAGE,
+        }
+    }
+
+    pub fn message(&self, host: &str) -> String {
+        match self {
+            Exceeded::ValueSize(max) => format!("Values of {host} are limited to {max} bytes"),
+            Exceeded::Bytes(max) => format!("Quota of {max} bytes reached for {host}"),
+            Exceeded::Keys(max) => format!("Quota of {max} keys reached for {host}"),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Quotas {
+    default: Limits,
+    hosts: HashMap<String, Limits>,
+}
+
+impl Quotas {
+    pub fn new(rules: Vec<QuotaRule>) -> Self {
+        let mut quotas = Quotas::default();
+        for rule in rules {
+            match rule.host {
+                Some(host) => {
+                    let limits = quotas.hosts.entry(host).or_default();
+                    *limits = rule.limits.or(*limits);
+                }
+                None => quotas.default = rule.limits.or(quotas.default),
+            }
+        }
+        quotas
+    }
+
+    pub fn limits(&self, host: &str) -> Limits {
+        self.hosts
+            .get(host)
+            .copied()
+            .unwrap_or_default()
+            .or(self.default)
+    }
+
+    /// Check that a host can store a value of a given size under a key, knowing how much it
+    /// already stores and what is replaced (the size of the key and value, if the key exists).
+    pub fn check(
+        &self,
+        host: &str,
+        usage: HostUsage,
+        key_size: usize,
+        value_size: usize,
+        replaced: Option<usize>,
+    ) -> Result<(), Exceeded> {
+        let limits = self.limits(host);
+        if let Some(max) = limits.value_size.filter(|&max| value_size > max) {
+            return Err(Exceeded::ValueSize(max));
+        }
+        let bytes = usage.bytes - replaced.unwrap_or(0) + key_size + value_size;
+        if let Some(max) = limits.bytes.filter(|&max| bytes > max) {
+            return Err(Exceeded::Bytes(max));
+        }
+        let keys = usage.keys + usize::from(replaced.is_none());
+        if let Some(max) = limits.keys.filter(|&max| keys > max) {
+            return Err(Exceeded::Keys(max));
+        }
+        Ok(())
+    }
+}
+
+/// Report what a host stores, and its limits.
+pub async fn usage(state: State, host: &str) -> Result<Response<Body>> {
+    let usage = state.kv.read().await.host_usage(host);
+    let limits = state.quotas.limits(host);
+    let body = json!({
+        "host": host,
+        "bytes": usage.bytes,
+        "keys": usage.keys,
+        "limits": {
+            "bytes": limits.bytes,
+            "keys": limits.keys,
+            "value_size": limits.value_size,
+        },
+    });
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(body.to_string().into())
+        .context("Could not build usage response")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quotas(rules: &[&str]) -> Quotas {
+        Quotas::new(rules.iter().map(|r| r.parse().unwrap()).collect())
+    }
+
+    fn usage(bytes: usize, keys: usize) -> HostUsage {
+        HostUsage { bytes, keys }
+    }
+
+    #[test]
+    fn parse_rule() {
+        let rule: QuotaRule = "bytes=1M,keys=10".parse().unwrap();
+        assert_eq!(rule.host, None);
+        assert_eq!(
+            rule.limits,
+            Limits {
+                bytes: Some(1 << 20),
+                keys: Some(10),
+                value_size: None,
+            }
+        );
+        let rule: QuotaRule = "Example.com:80=value-size=4k".parse().unwrap();
+        assert_eq!(rule.host.as_deref(), Some("example.com"));
+        assert_eq!(rule.limits.value_size, Some(4096));
+        assert_eq!(rule.limits.bytes, None);
+    }
+
+    #[test]
+    fn parse_invalid_rule() {
+        let error = |s: &str| s.parse::<QuotaRule>().unwrap_err();
+        assert_eq!(error("bytes"), "Invalid limit \"bytes\"");
+        assert_eq!(error("keys=ten"), "Invalid value for keys: \"ten\"");
+        assert_eq!(error("bytes=1T"), "Invalid value for bytes: \"1T\"");
+        assert_eq!(error("example.com=size=1"), "Unknown limit \"size\"");
+        assert_eq!(error("a/b=keys=1"), "Invalid host \"a/b\"");
+    }
+
+    #[test]
+    fn host_limits_fall_back_to_default() {
+        let quotas = quotas(&[
+            "bytes=100,keys=2",
+            "example.com=keys=5",
+            "example.com=bytes=1k",
+        ]);
+        let limits = quotas.limits("example.com");
+        assert_eq!(limits.bytes, Some(1024));
+        assert_eq!(limits.keys, Some(5));
+        let limits = quotas.limits("example.net");
+        assert_eq!(limits.bytes, Some(100));
+        assert_eq!(limits.keys, Some(2));
+    }
+
+    #[test]
+    fn check() {
+        let quotas = quotas(&["bytes=100,keys=2,value-size=50"]);
+        let check =
+            |usage, value_size, replaced| quotas.check("h", usage, 10, value_size, replaced);
+        assert_eq!(check(usage(0, 0), 50, None), Ok(()));
+        assert_eq!(check(usage(0, 0), 51, None), Err(Exceeded::ValueSize(50)));
+        assert_eq!(check(usage(40, 1), 50, None), Ok(()));
+        assert_eq!(check(usage(41, 1), 50, None), Err(Exceeded::Bytes(100)));
+        assert_eq!(check(usage(20, 2), 10, None), Err(Exceeded::Keys(2)));
+    }
+
+    #[test]
+    fn check_replacement() {
+        let quotas = quotas(&["bytes=100,keys=2"]);
+        let check =
+            |usage, value_size, replaced| quotas.check("h", usage, 10, value_size, replaced);
+        // Replacing a value doesn't add a key, and frees the bytes it used.
+        assert_eq!(check(usage(20, 2), 10, Some(20)), Ok(()));
+        assert_eq!(check(usage(40, 2), 70, Some(20)), Ok(()));
+        assert_eq!(
+            check(usage(100, 2), 81, Some(20)),
+            Err(Exceeded::Bytes(100))
+        );
+    }
+
+    #[test]
+    fn unlimited() {
+        let quotas = quotas(&[]);
+        assert_eq!(
+            quotas.check("h", usage(1 << 30, 1 << 20), 10, 1 << 30, None),
+            Ok(())
+        );
+    }
+}
@@ -0,0 +1,109 @@
+// Copyright 2023, Antoine Catton
+//
+// Permission to use, copy, modify, and/or distribute this software for any purpose with or without
+// fee is hereby granted, provided that the above copyright notice and this permission notice
+// appear in all copies.
+//
+// THE SOFTWARE IS PROVIDED “AS IS” AND THE AUTHOR DISCLAIMS ALL WARRANTIES WITH REGARD TO THIS
+// SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE
+// AUTHOR BE LIABLE FOR ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+// WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN ACTION OF CONTRACT,
+// NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF OR IN CONNECTION WITH THE USE OR PERFORMANCE
+// OF THIS SOFTWARE.
+
+//! Accepting connections and serving them with the handler.
+
+use std::fmt::Display;
+
+use anyhow::{Context, Result};
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::task;
+use tokio_rustls::TlsAcceptor;
+
+use crate::listener::{Listener, Mode};
+use crate::shutdown::Shutdown;
+use crate::{handler, State};
+
+/// Serve a single connection. HTTP/1.1 and HTTP/2 are both accepted, so cleartext HTTP/2 works
+/// with prior knowledge.
+async fn serve_connection<S>(
+    stream: S,
+    peer: impl Display,
+    http: Http,
+    state: State,
+    mode: Mode,
+    mut shutdown: Shutdown,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let service = service_fn(move |req| handler(state.clone(), mode, req));
+    let connection = http.serve_connection(stream, service);
+    tokio::pin!(connection);
+    let result = tokio::select! {
+        res = &mut connection => res,
+        _ = shutdown.requested() => {
+            // Let the requests in flight finish, but don't accept new ones.
+            connection.as_mut().graceful_shutdown();
+            connection.await
+        }
+    };
+    if let Err(err) = result {
+        log::debug!("Connection with {peer} failed: {err}");
+    }
+}
+
+/// Serve a connection, after the TLS handshake if needed.
+async fn serve_stream<S>(
+    stream: S,
+    peer: String,
+    tls: Option<TlsAcceptor>,
+    http: Http,
+    state: State,
+    mode: Mode,
+    shutdown: Shutdown,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    match tls {
+        None => serve_connection(stream, peer, http, state, mode, shutdown).await,
+        Some(acceptor) => match acceptor.accept(stream).await {
+            Ok(stream) => serve_connection(stream, peer, http, state, mode, shutdown).await,
+            Err(err) => log::debug!("TLS handshake with {peer} failed: {err}"),
+        },
+    }
+}
+
+pub async fn serve(
+    listener: Listener,
+    mode: Mode,
+    tls: Option<TlsAcceptor>,
+    http: Http,
+    state: State,
+    mut shutdown: Shutdown,
+) -> Result<()> {
+    loop {
+        let (tls, http, state) = (tls.clone(), http.clone(), state.clone());
+        match &listener {
+            Listener::Tcp(listener) => {
+                let (stream, peer) = tokio::select! {
+                    res = listener.accept() => res.context("Could not accept")?,
+                    _ = shutdown.requested() => return Ok(()),
+                };
+                let peer = peer.to_string();
+                let shutdown = shutdown.clone();
+                task::spawn(serve_stream(stream, peer, tls, http, state, mode, shutdown));
+            }
+            Listener::Unix(listener) => {
+                let (stream, _) = tokio::select! {
+                    res = listener.accept() => res.context("Could not accept")?,
+                    _ = shutdown.requested() => return Ok(()),
+                };
+                let peer = "unix socket".to_owned();
+                let shutdown = shutdown.clone();
+                task::spawn(serve_stream(stream, peer, tls, http, state, mode, shutdown));
+            }
+        }
+    }
+}
@@ -0,0 +1,78 @@
+// Copyright 2023, Antoine Catton
+//
+// Permission to use, copy, modify, and/or distribute this software for any purpose with or without
+// fee is hereby granted, provided that the above copyright notice and this permission notice
+// appear in all copies.
+//
+// THE SOFTWARE IS PROVIDED “AS IS” AND THE AUTHOR DISCLAIMS ALL WARRANTIES WITH REGARD TO THIS
+// SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE
+// AUTHOR BE LIABLE FOR ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+// WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN ACTION OF CONTRACT,
+// NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF OR IN CONNECTION WITH THE USE OR PERFORMANCE
+// OF THIS SOFTWARE.
+
+//! Graceful shutdown: the tasks are told to stop, and then waited for.
+
+use tokio::sync::{mpsc, watch};
+use tokio::time::{self, Duration};
+
+/// Held by every task which needs to finish before exiting (listeners, connections, ...).
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    requested: watch::Receiver<bool>,
+    /// Never used to send anything, the channel is closed once all the tasks dropped it.
+    _running: mpsc::Sender<()>,
+}
+
+impl Shutdown {
+    /// Wait until the shutdown is requested.
+    pub async fn requested(&mut self) {
+        while !*self.requested.borrow() {
+            if self.requested.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Drain {
+    request: watch::Sender<bool>,
+    running: mpsc::Receiver<()>,
+    shutdown: Shutdown,
+}
+
+impl Default for Drain {
+    fn default() -> Self {
+        let (request, requested) = watch::channel(false);
+        let (running_send, running) = mpsc::channel(1);
+        Drain {
+            request,
+            running,
+            shutdown: Shutdown {
+                requested,
+                _running: running_send,
+            },
+        }
+    }
+}
+
+impl Drain {
+    pub fn shutdown(&self) -> Shutdown {
+        self.shutdown.clone()
+    }
+
+    /// Request the shutdown, and wait for all the tasks to finish. Returns false if some tasks
+    /// were still running after the timeout.
+    pub async fn drain(self, timeout: Duration) -> bool {
+        let Drain {
+            request,
+            mut running,
+            shutdown,
+        } = self;
+        drop(shutdown);
+        // Nobody listening only means that every task is already gone.
+        let _ = request.send(true);
+        time::timeout(timeout, running.recv()).await.is_ok()
+    }
+}
@@ -0,0 +1,374 @@
+// Copyright 2023, Antoine Catton
+//
+// Permission to use, copy, modify, and/or distribute this software for any purpose with or without
+// fee is hereby granted, provided that the above copyright notice and this permission notice
+// appear in all copies.
+//
+// THE SOFTWARE IS PROVIDED “AS IS” AND THE AUTHOR DISCLAIMS ALL WARRANTIES WITH REGARD TO THIS
+// SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE
+// AUTHOR BE LIABLE FOR ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+// WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN ACTION OF CONTRACT,
+// NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF OR IN CONNECTION WITH THE USE OR PERFORMANCE
+// OF THIS SOFTWARE.
+
+//! Snapshots of the whole store, saved to disk and restored at startup.
+//!
+//! A snapshot starts with a magic number followed by the number of records. Each record contains
+//! the key, the value, the stored headers, the modification time, the expiration time (zero if
+//! the value never expires) and the duration of a sliding expiration (zero if it's fixed). Times
+//! are milliseconds since the Unix epoch, all the integers are big-endian, and strings are prefixed
+//! by their length.
+
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use http::header::{HeaderMap, HeaderName, HeaderValue};
+use tokio::task;
+use tokio::time::{self, Instant};
+
+use crate::shutdown::Shutdown;
+use crate::{conditional, Entry, Sliding, State, Store};
+
+const MAGIC: &[u8; 8] = b"MHTTPD\x00\x01";
+
+fn to_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+fn from_millis(millis: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(millis)
+}
+
+/// Milliseconds since the Unix epoch at which a deadline is reached (never 0).
+pub fn deadline_millis(deadline: Instant) -> u64 {
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    to_millis(SystemTime::now() + remaining).max(1)
+}
+
+/// The deadline reached at a number of milliseconds since the Unix epoch, None if it's in the past.
+fn millis_deadline(millis: u64) -> Option<Instant> {
+    let remaining = from_millis(millis).duration_since(SystemTime::now()).ok()?;
+    Some(Instant::now() + remaining)
+}
+
+pub fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend((bytes.len() as u64).to_be_bytes());
+    buf.extend(bytes);
+}
+
+/// Encode one key and its value in the snapshot format.
+pub fn put_entry(buf: &mut Vec<u8>, key: &str, entry: &Entry) {
+    let sliding = entry.sliding.as_ref().map(|sliding| sliding.ttl);
+    put_entry_expiring(buf, key, entry, entry.expires(), sliding)
+}
+
+/// Encode one key and its value, with another expiration than the entry's.
+pub fn put_entry_expiring(
+    buf: &mut Vec<u8>,
+    key: &str,
+    entry: &Entry,
+    expires: Option<Instant>,
+    sliding: Option<Duration>,
+) {
+    put_bytes(buf, key.as_bytes());
+    put_bytes(buf, &entry.value);
+    buf.extend((entry.headers.len() as u64).to_be_bytes());
+    for (name, value) in &entry.headers {
+        put_bytes(buf, name.as_str().as_bytes());
+        put_bytes(buf, value.as_bytes());
+    }
+    buf.extend(to_millis(entry.modified).to_be_bytes());
+    let expires = expires.map(deadline_millis).unwrap_or(0);
+    buf.extend(expires.to_be_bytes());
+    let sliding = sliding.map_or(0, |ttl| ttl.as_millis() as u64);
+    buf.extend(sliding.to_be_bytes());
+}
+
+fn encode(kv: &Store) -> Vec<u8> {
+    let mut buf = MAGIC.to_vec();
+    buf.extend((kv.len() as u64).to_be_bytes());
+    for (key, entry) in kv.iter() {
+        put_entry(&mut buf, key, entry);
+    }
+    buf
+}
+
+pub struct Reader<'a>(pub &'a [u8]);
+
+impl<'a> Reader<'a> {
+    pub fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        anyhow::ensure!(self.0.len() >= len, "Truncated data");
+        let (head, tail) = self.0.split_at(len);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    pub fn u64(&mut self) -> Result<u64> {
+        let bytes = self.take(8)?;
+        Ok(u64::from_be_bytes(bytes.try_into()?))
+    }
+
+    pub fn bytes(&mut self) -> Result<&'a [u8]> {
+        let len = self.u64()?.try_into()?;
+        self.take(len)
+    }
+}
+
+/// A key and its value, as decoded from a snapshot.
+#[derive(Debug)]
+pub struct Record {
+    pub key: String,
+    value: Vec<u8>,
+    headers: HeaderMap,
+    modified: SystemTime,
+    /// Milliseconds since the Unix epoch.
+    expires: Option<u64>,
+    /// Milliseconds by which each read postpones the expiration.
+    sliding: Option<u64>,
+}
+
+impl Record {
+    pub fn decode(reader: &mut Reader) -> Result<Self> {
+        let key = String::from_utf8(reader.bytes()?.to_vec()).context("Invalid key")?;
+        let value = reader.bytes()?.to_vec();
+        let mut headers = HeaderMap::new();
+        for _ in 0..reader.u64()? {
+            let name = HeaderName::from_bytes(reader.bytes()?).context("Invalid header name")?;
+            let value = HeaderValue::from_bytes(reader.bytes()?).context("Invalid header value")?;
+            headers.append(name, value);
+        }
+        let modified = from_millis(reader.u64()?);
+        let expires = Some(reader.u64()?).filter(|&e| e > 0);
+        let sliding = Some(reader.u64()?).filter(|&s| s > 0);
+        Ok(Record {
+            key,
+            value,
+            headers,
+            modified,
+            expires,
+            sliding,
+        })
+    }
+
+    /// Turn the record back into an entry of the store, None if the value already expired.
+    pub fn into_entry(self, state: &State) -> Option<(String, Entry)> {
+        let deadline = match self.expires {
+            None => None,
+            Some(expires) => Some(millis_deadline(expires)?),
+        };
+        let sliding = self
+            .sliding
+            .zip(deadline)
+            .map(|(ms, deadline)| Sliding::new(Duration::from_millis(ms), deadline));
+        let entry = Entry {
+            etag: conditional::etag(&self.value),
+            value: self.value,
+            generation: state.next_generation(),
+            headers: self.headers,
+            modified: self.modified,
+            deadline,
+            sliding,
+            usage: Default::default(),
+        };
+        Some((self.key, entry))
+    }
+}
+
+fn decode(data: &[u8]) -> Result<Vec<Record>> {
+    let mut reader = Reader(data);
+    anyhow::ensure!(reader.take(MAGIC.len())? == MAGIC, "Not a snapshot file");
+    let count = reader.u64()?;
+    let mut records = Vec::new();
+    for _ in 0..count {
+        records.push(Record::decode(&mut reader)?);
+    }
+    anyhow::ensure!(reader.0.is_empty(), "Trailing data at the end of snapshot");
+    Ok(records)
+}
+
+/// Write the file next to its destination and rename it, so that a crash never leaves a partially
+/// written file behind.
+pub fn write_atomically(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+
+    let mut file = File::create(&tmp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        File::open(parent)?.sync_all()?;
+    }
+    Ok(())
+}
+
+pub async fn save(state: &State, path: &Path) -> Result<()> {
+    let data = encode(&*state.kv.read().await);
+    let path = path.to_owned();
+    task::spawn_blocking(move || write_atomically(&path, &data))
+        .await
+        .context("Snapshot writer panicked")?
+        .context("Could not write snapshot")?;
+    log::debug!("Snapshot saved");
+    Ok(())
+}
+
+pub async fn restore(state: &State, path: &Path) -> Result<()> {
+    let data = {
+        let path = path.to_owned();
+        task::spawn_blocking(move || fs::read(path))
+            .await
+            .context("Snapshot reader panicked")?
+    };
+    let data = match data {
+        Ok(data) => data,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            log::info!("No snapshot found at {path}", path = path.display());
+            return Ok(());
+        }
+        Err(err) => return Err(err).context("Could not read snapshot"),
+    };
+    let records = decode(&data).context("Could not decode snapshot")?;
+
+    let mut restored = 0;
+    let mut write_kv = state.kv.write().await;
+    // Values which expired while the server was down are skipped.
+    for (key, entry) in records.into_iter().filter_map(|r| r.into_entry(state)) {
+        state.insert(&mut write_kv, key, entry);
+        restored += 1;
+    }
+    log::info!("Restored {restored} values from snapshot");
+    Ok(())
+}
+
+/// Save a snapshot at every interval, until the shutdown is requested.
+pub async fn periodic(state: State, path: PathBuf, interval: Duration, mut shutdown: Shutdown) {
+    let mut ticker = time::interval_at(Instant::now() + interval, interval);
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {
+                if let Err(err) = save(&state, &path).await {
+                    log::error!("Could not save snapshot: {err:#}");
+                }
+            }
+            _ = shutdown.requested() => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use http::header;
+
+    use crate::tests::entry;
+
+    #[tokio::test]
+    async fn round_trip() {
+        let state = crate::tests::state();
+        let deadline = Instant::now() + Duration::from_secs(60);
+        let mut kv = Store::default();
+        let mut plain = entry(&state, b"plain", None);
+        plain
+            .headers
+            .insert(header::CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+        let mut sliding = entry(&state, b"sliding", Some(deadline));
+        sliding.sliding = Some(Sliding::new(Duration::from_secs(60), deadline));
+        state.insert(&mut kv, "example.com/plain".to_owned(), plain);
+        state.insert(&mut kv, "example.com/sliding".to_owned(), sliding);
+
+        let records = decode(&encode(&kv)).unwrap();
+        let entries: Vec<_> = records
+            .into_iter()
+            .map(|r| r.into_entry(&state).unwrap())
+            .collect();
+        let [(plain_key, plain), (sliding_key, sliding)] = &entries[..] else {
+            panic!("Expected 2 entries, got {}", entries.len());
+        };
+        assert_eq!(plain_key, "example.com/plain");
+        assert_eq!(plain.value, b"plain");
+        assert_eq!(plain.etag, conditional::etag(b"plain"));
+        assert_eq!(plain.headers[header::CONTENT_TYPE], "text/plain");
+        assert_eq!(plain.expires(), None);
+        assert_eq!(sliding_key, "example.com/sliding");
+        assert_eq!(sliding.value, b"sliding");
+        let ttl = sliding.sliding.as_ref().map(|s| s.ttl);
+        assert_eq!(ttl, Some(Duration::from_secs(60)));
+        let remaining = sliding.expires().unwrap() - Instant::now();
+        assert!(remaining > Duration::from_secs(59) && remaining <= Duration::from_secs(60));
+    }
+
+    #[tokio::test]
+    async fn other_expiration() {
+        let state = crate::tests::state();
+        let entry = entry(&state, b"value", None);
+        let deadline = Instant::now() + Duration::from_secs(60);
+        let mut buf = Vec::new();
+        put_entry_expiring(&mut buf, "example.com/key", &entry, Some(deadline), None);
+        let record = Record::decode(&mut Reader(&buf)).unwrap();
+        assert_eq!(record.expires, Some(deadline_millis(deadline)));
+        assert_eq!(record.sliding, None);
+        let (_, entry) = record.into_entry(&state).unwrap();
+        assert!(entry.expires().is_some_and(|e| e <= deadline));
+    }
+
+    #[tokio::test]
+    async fn already_expired() {
+        let state = crate::tests::state();
+        let mut buf = Vec::new();
+        put_bytes(&mut buf, b"example.com/key");
+        put_bytes(&mut buf, b"value");
+        buf.extend(0u64.to_be_bytes());
+        buf.extend(1u64.to_be_bytes());
+        // Expired 1ms after the Unix epoch.
+        buf.extend(1u64.to_be_bytes());
+        buf.extend(0u64.to_be_bytes());
+        let record = Record::decode(&mut Reader(&buf)).unwrap();
+        assert_eq!(record.expires, Some(1));
+        assert!(record.into_entry(&state).is_none());
+    }
+
+    #[test]
+    fn not_a_snapshot() {
+        let err = decode(b"NOTHTTPD\x00\x00\x00\x00\x00\x00\x00\x00").unwrap_err();
+        assert_eq!(err.to_string(), "Not a snapshot file");
+    }
+
+    #[tokio::test]
+    async fn trailing_data() {
+        let state = crate::tests::state();
+        let mut kv = Store::default();
+        state.insert(
+            &mut kv,
+            "example.com/key".to_owned(),
+            entry(&state, b"v", None),
+        );
+        let mut data = encode(&kv);
+        data.push(0);
+        let err = decode(&data).unwrap_err();
+        assert_eq!(err.to_string(), "Trailing data at the end of snapshot");
+    }
+
+    #[tokio::test]
+    async fn truncated_data() {
+        let state = crate::tests::state();
+        let mut kv = Store::default();
+        state.insert(
+            &mut kv,
+            "example.com/key".to_owned(),
+            entry(&state, b"v", None),
+        );
+        let data = encode(&kv);
+        for len in MAGIC.len()..data.len() {
+            let err = decode(&data[..len]).unwrap_err();
+            assert_eq!(err.to_string(), "Truncated data", "Truncated at {len}");
+        }
+    }
+}
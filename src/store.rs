@@ -0,0 +1,312 @@
+// Copyright 2023, Antoine Catton
+//
+// Permission to use, copy, modify, and/or distribute this software for any purpose with or without
+// fee is hereby granted, provided that the above copyright notice and this permission notice
+// appear in all copies.
+//
+// THE SOFTWARE IS PROVIDED “AS IS” AND THE AUTHOR DISCLAIMS ALL WARRANTIES WITH REGARD TO THIS
+// SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE
+// AUTHOR BE LIABLE FOR ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+// WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN ACTION OF CONTRACT,
+// NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF OR IN CONNECTION WITH THE USE OR PERFORMANCE
+// OF THIS SOFTWARE.
+
+//! The key-value store, keeping track of the memory it uses, and the eviction of values when it
+//! uses too much.
+
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Deref;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use clap::ValueEnum;
+use tokio::time::{Duration, Instant};
+
+use crate::{Entry, Sliding};
+
+/// How the entries are used, to choose which ones to evict. Atomics are used so that they can be
+/// updated while only holding the read lock.
+#[derive(Debug, Default)]
+pub struct Usage {
+    /// Value of the store's clock at the last access.
+    accessed: AtomicU64,
+    hits: AtomicU64,
+}
+
+/// Which values to evict when the memory limit is reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Eviction {
+    /// Don't evict anything, refuse the new values with 507 Insufficient Storage.
+    Reject,
+    /// Evict the least recently used values.
+    Lru,
+    /// Evict the least frequently used values.
+    Lfu,
+    /// Evict the values expiring the soonest, values without expiration are never evicted.
+    SoonestExpiring,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryLimit {
+    pub max: usize,
+    pub eviction: Eviction,
+}
+
+/// Memory accounted for an entry: its key and its value.
+fn size(key: &str, entry: &Entry) -> usize {
+    key.len() + entry.value.len()
+}
+
+/// The host part of a key. Keys are the host followed by the path, which starts with a slash.
+pub fn host_of(key: &str) -> &str {
+    key.split_once('/').map(|(host, _)| host).unwrap_or(key)
+}
+
+/// What a host stores.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct HostUsage {
+    pub bytes: usize,
+    pub keys: usize,
+}
+
+/// Values are ordered by key, so that all the keys under a prefix can be listed.
+#[derive(Debug, Default)]
+pub(crate) struct Store {
+    entries: BTreeMap<String, Entry>,
+    /// Bytes used by the keys and the values.
+    used: usize,
+    hosts: HashMap<String, HostUsage>,
+    /// Logical clock, ticking on every access.
+    clock: AtomicU64,
+}
+
+impl Deref for Store {
+    type Target = BTreeMap<String, Entry>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.entries
+    }
+}
+
+impl Store {
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    /// Memory used by a key and its value, if it exists.
+    pub fn entry_size(&self, key: &str) -> Option<usize> {
+        self.entries.get(key).map(|e| size(key, e))
+    }
+
+    pub fn host_usage(&self, host: &str) -> HostUsage {
+        self.hosts.get(host).copied().unwrap_or_default()
+    }
+
+    fn tick(&self) -> u64 {
+        self.clock.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Get an entry unless it expired, even if the expiration task didn't remove it yet.
+    pub fn live(&self, key: &str) -> Option<&Entry> {
+        let now = Instant::now();
+        self.entries.get(key).filter(|entry| !entry.expired(now))
+    }
+
+    /// Get an entry unless it expired, recording the access for the eviction.
+    pub fn touch(&self, key: &str) -> Option<&Entry> {
+        let entry = self.live(key)?;
+        entry.usage.accessed.store(self.tick(), Ordering::Relaxed);
+        entry.usage.hits.fetch_add(1, Ordering::Relaxed);
+        Some(entry)
+    }
+
+    pub fn insert(&mut self, key: String, entry: Entry) {
+        self.remove(&key);
+        entry.usage.accessed.store(self.tick(), Ordering::Relaxed);
+        let size = size(&key, &entry);
+        self.used += size;
+        let usage = self.hosts.entry(host_of(&key).to_owned()).or_default();
+        usage.bytes += size;
+        usage.keys += 1;
+        self.entries.insert(key, entry);
+    }
+
+    /// Change when an entry expires, along with its generation so that the expiration scheduled
+    /// for the previous deadline is ignored. A sliding expiration needs its duration. Returns false
+    /// if the key doesn't exist.
+    pub fn set_deadline(
+        &mut self,
+        key: &str,
+        deadline: Option<Instant>,
+        sliding: Option<Duration>,
+        generation: u64,
+    ) -> bool {
+        match self.entries.get_mut(key) {
+            Some(entry) => {
+                entry.deadline = deadline;
+                entry.generation = generation;
+                entry.sliding = sliding
+                    .zip(deadline)
+                    .map(|(ttl, deadline)| Sliding::new(ttl, deadline));
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<Entry> {
+        let entry = self.entries.remove(key)?;
+        let size = size(key, &entry);
+        self.used -= size;
+        let host = host_of(key);
+        if let Some(usage) = self.hosts.get_mut(host) {
+            usage.bytes -= size;
+            usage.keys -= 1;
+            if usage.keys == 0 {
+                self.hosts.remove(host);
+            }
+        }
+        Some(entry)
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.hosts.clear();
+        self.used = 0;
+    }
+
+    /// The keys to evict so that a value of the given size can be stored under a key, or None if
+    /// not enough memory can be freed. The key itself is never evicted, as it's being replaced.
+    pub fn victims(&self, limit: MemoryLimit, key: &str, value_size: usize) -> Option<Vec<String>> {
+        let replaced = self.entry_size(key).unwrap_or(0);
+        let needed = self.used - replaced + key.len() + value_size;
+        if needed <= limit.max {
+            return Some(Vec::new());
+        }
+        let now = Instant::now();
+        // Sorted by (score, tie breaker), the lowest score is evicted first.
+        let mut candidates: Vec<((u64, u64), &str, usize)> = self
+            .entries
+            .iter()
+            .filter(|(k, _)| k.as_str() != key)
+            .filter_map(|(k, e)| {
+                let accessed = e.usage.accessed.load(Ordering::Relaxed);
+                let score = match limit.eviction {
+                    Eviction::Reject => return None,
+                    Eviction::Lru => (accessed, 0),
+                    Eviction::Lfu => (e.usage.hits.load(Ordering::Relaxed), accessed),
+                    Eviction::SoonestExpiring => {
+                        let remaining = e.expires()?.saturating_duration_since(now);
+                        (remaining.as_millis() as u64, accessed)
+                    }
+                };
+                Some((score, k.as_str(), size(k, e)))
+            })
+            .collect();
+        candidates.sort_unstable_by_key(|(score, _, _)| *score);
+        let mut freed = 0;
+        let mut victims = Vec::new();
+        for (_, k, size) in candidates {
+            if needed - freed <= limit.max {
+                break;
+            }
+            freed += size;
+            victims.push(k.to_owned());
+        }
+        (needed - freed <= limit.max).then_some(victims)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::tests::{entry, state};
+
+    /// Each key and its value use 10 bytes. "h/a" is the most recently and frequently used, "h/c"
+    /// was never read and has no expiration, and "h/b" expires the soonest.
+    fn store() -> Store {
+        let state = state();
+        let mut kv = Store::default();
+        let now = Instant::now();
+        let expiring = [
+            ("h/a", Some(now + Duration::from_secs(10))),
+            ("h/b", Some(now + Duration::from_secs(5))),
+            ("h/c", None),
+        ];
+        for (key, deadline) in expiring {
+            kv.insert(key.to_owned(), entry(&state, b"1234567", deadline));
+        }
+        kv.touch("h/b");
+        kv.touch("h/a");
+        kv.touch("h/a");
+        kv
+    }
+
+    fn limit(eviction: Eviction) -> MemoryLimit {
+        MemoryLimit { max: 30, eviction }
+    }
+
+    fn victims(eviction: Eviction, key: &str, value_size: usize) -> Option<Vec<String>> {
+        store().victims(limit(eviction), key, value_size)
+    }
+
+    #[test]
+    fn accounting() {
+        let mut kv = store();
+        assert_eq!(kv.used(), 30);
+        assert_eq!(kv.host_usage("h"), HostUsage { bytes: 30, keys: 3 });
+        kv.remove("h/a");
+        assert_eq!(kv.used(), 20);
+        assert_eq!(kv.host_usage("h"), HostUsage { bytes: 20, keys: 2 });
+    }
+
+    #[test]
+    fn enough_memory() {
+        for eviction in [Eviction::Reject, Eviction::Lru] {
+            assert_eq!(victims(eviction, "h/a", 7), Some(Vec::new()));
+        }
+    }
+
+    #[test]
+    fn reject() {
+        assert_eq!(victims(Eviction::Reject, "h/d", 7), None);
+    }
+
+    #[test]
+    fn lru() {
+        assert_eq!(victims(Eviction::Lru, "h/d", 7).unwrap(), ["h/c"]);
+        assert_eq!(victims(Eviction::Lru, "h/d", 17).unwrap(), ["h/c", "h/b"]);
+    }
+
+    #[test]
+    fn lfu() {
+        assert_eq!(victims(Eviction::Lfu, "h/d", 7).unwrap(), ["h/c"]);
+        assert_eq!(victims(Eviction::Lfu, "h/d", 17).unwrap(), ["h/c", "h/b"]);
+        let kv = store();
+        kv.touch("h/c");
+        kv.touch("h/c");
+        kv.touch("h/c");
+        let victims = kv.victims(limit(Eviction::Lfu), "h/d", 7);
+        assert_eq!(victims.unwrap(), ["h/b"]);
+    }
+
+    #[test]
+    fn soonest_expiring() {
+        let eviction = Eviction::SoonestExpiring;
+        assert_eq!(victims(eviction, "h/d", 7).unwrap(), ["h/b"]);
+        assert_eq!(victims(eviction, "h/d", 17).unwrap(), ["h/b", "h/a"]);
+        // Values without expiration are never evicted.
+        assert_eq!(victims(eviction, "h/d", 27), None);
+    }
+
+    #[test]
+    fn replaced_key_not_evicted() {
+        assert_eq!(victims(Eviction::Lru, "h/c", 17).unwrap(), ["h/b"]);
+        assert_eq!(victims(Eviction::Lfu, "h/c", 17).unwrap(), ["h/b"]);
+        let eviction = Eviction::SoonestExpiring;
+        assert_eq!(victims(eviction, "h/b", 17).unwrap(), ["h/a"]);
+        assert_eq!(victims(eviction, "h/b", 27), None);
+        // Replacing a value by a bigger one than the limit can't free enough memory.
+        assert_eq!(victims(Eviction::Lru, "h/a", 28), None);
+    }
+}
@@ -0,0 +1,181 @@
+// Copyright 2023, Antoine Catton
+//
+// Permission to use, copy, modify, and/or distribute this software for any purpose with or without
+// fee is hereby granted, provided that the above copyright notice and this permission notice
+// appear in all copies.
+//
+// THE SOFTWARE IS PROVIDED “AS IS” AND THE AUTHOR DISCLAIMS ALL WARRANTIES WITH REGARD TO THIS
+// SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE
+// AUTHOR BE LIABLE FOR ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+// WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN ACTION OF CONTRACT,
+// NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF OR IN CONNECTION WITH THE USE OR PERFORMANCE
+// OF THIS SOFTWARE.
+
+//! Integration with systemd: socket activation, and readiness and watchdog notifications.
+//!
+//! See sd_listen_fds(3) and sd_notify(3).
+
+use std::env;
+use std::io;
+use std::os::fd::{FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixDatagram;
+use std::process;
+
+use anyhow::{Context, Result};
+use tokio::time::{self, Duration};
+
+/// First file descriptor passed by systemd.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Parse the socket activation variables, returning the name of each file descriptor. The file
+/// descriptors are only for us if LISTEN_PID is our pid.
+fn parse_listen_fds(
+    listen_pid: Option<&str>,
+    listen_fds: Option<&str>,
+    listen_fdnames: Option<&str>,
+    pid: u32,
+) -> Result<Vec<(String, RawFd)>> {
+    let (listen_pid, listen_fds) = match (listen_pid, listen_fds) {
+        (Some(listen_pid), Some(listen_fds)) => (listen_pid, listen_fds),
+        _ => return Ok(Vec::new()),
+    };
+    if listen_pid.parse::<u32>().context("Invalid LISTEN_PID")? != pid {
+        return Ok(Vec::new());
+    }
+    let count: RawFd = listen_fds.parse().context("Invalid LISTEN_FDS")?;
+    let names: Vec<&str> = listen_fdnames
+        .map(|n| n.split(':').collect())
+        .unwrap_or_default();
+    Ok((0..count)
+        .map(|i| {
+            // systemd names the sockets "unknown" when they don't have a name.
+            let name = names.get(i as usize).copied().unwrap_or("unknown");
+            (name.to_owned(), LISTEN_FDS_START + i)
+        })
+        .collect())
+}
+
+/// Take the sockets passed by systemd, with their names. The environment variables are removed,
+/// so that they are not inherited by child processes. This must be called before any thread is
+/// started, since changing the environment is racing with other threads reading it.
+pub fn listen_fds() -> Result<Vec<(String, OwnedFd)>> {
+    let [pid, fds, names] = ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"].map(|var| {
+        let value = env::var(var).ok();
+        env::remove_var(var);
+        value
+    });
+    let fds = parse_listen_fds(
+        pid.as_deref(),
+        fds.as_deref(),
+        names.as_deref(),
+        process::id(),
+    )?;
+    Ok(fds
+        .into_iter()
+        .map(|(name, fd)| {
+            // SAFETY: systemd passed this file descriptor to us, nothing else owns it.
+            let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+            (name, fd)
+        })
+        .collect())
+}
+
+/// Send a notification to the given socket, which is a path or an abstract name starting with "@".
+fn notify_socket(socket: &str, message: &str) -> io::Result<()> {
+    let datagram = UnixDatagram::unbound()?;
+    match socket.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            datagram.send_to_addr(message.as_bytes(), &addr)?;
+        }
+        _ => {
+            datagram.send_to(message.as_bytes(), socket)?;
+        }
+    }
+    Ok(())
+}
+
+/// Notify systemd of a state change (e.g. "READY=1"), if it's expecting notifications.
+pub fn notify(message: &str) {
+    if let Ok(socket) = env::var("NOTIFY_SOCKET") {
+        if let Err(err) = notify_socket(&socket, message) {
+            log::warn!("Could not notify systemd of \"{message}\": {err}");
+        }
+    }
+}
+
+fn parse_watchdog(
+    watchdog_usec: Option<&str>,
+    watchdog_pid: Option<&str>,
+    pid: u32,
+) -> Option<Duration> {
+    if let Some(watchdog_pid) = watchdog_pid {
+        if watchdog_pid.parse::<u32>().ok()? != pid {
+            return None;
+        }
+    }
+    let usec: u64 = watchdog_usec?.parse().ok().filter(|&u| u > 0)?;
+    Some(Duration::from_micros(usec))
+}
+
+/// Send keep-alives to the systemd watchdog, if it's enabled.
+pub async fn watchdog() {
+    let timeout = parse_watchdog(
+        env::var("WATCHDOG_USEC").ok().as_deref(),
+        env::var("WATCHDOG_PID").ok().as_deref(),
+        process::id(),
+    );
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return,
+    };
+    // Notify twice per period, as recommended, so that a bit of lag doesn't trigger the watchdog.
+    let mut ticker = time::interval(timeout / 2);
+    loop {
+        ticker.tick().await;
+        notify("WATCHDOG=1");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn listen_fds_for_another_process() {
+        let fds = parse_listen_fds(Some("1"), Some("2"), None, 1234).unwrap();
+        assert!(fds.is_empty());
+    }
+
+    #[test]
+    fn listen_fds_with_names() {
+        let fds = parse_listen_fds(Some("1234"), Some("3"), Some("http:admin"), 1234).unwrap();
+        let expected = [("http", 3), ("admin", 4), ("unknown", 5)];
+        let expected: Vec<_> = expected.map(|(n, fd)| (n.to_owned(), fd)).into();
+        assert_eq!(fds, expected);
+    }
+
+    #[test]
+    fn watchdog_period() {
+        let timeout = parse_watchdog(Some("3000000"), None, 1234);
+        assert_eq!(timeout, Some(Duration::from_secs(3)));
+        assert_eq!(parse_watchdog(Some("3000000"), Some("1"), 1234), None);
+        assert_eq!(parse_watchdog(None, None, 1234), None);
+    }
+
+    #[test]
+    fn notify_local_socket() {
+        let path = env::temp_dir().join(format!("memoryhttpd-notify-{}", process::id()));
+        let _ = std::fs::remove_file(&path);
+        let receiver = UnixDatagram::bind(&path).unwrap();
+
+        notify_socket(path.to_str().unwrap(), "READY=1").unwrap();
+
+        let mut buf = [0; 64];
+        let len = receiver.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,206 @@
+// Copyright 2023, Antoine Catton
+//
+// Permission to use, copy, modify, and/or distribute this software for any purpose with or without
+// fee is hereby granted, provided that the above copyright notice and this permission notice
+// appear in all copies.
+//
+// THE SOFTWARE IS PROVIDED “AS IS” AND THE AUTHOR DISCLAIMS ALL WARRANTIES WITH REGARD TO THIS
+// SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE
+// AUTHOR BE LIABLE FOR ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+// WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN ACTION OF CONTRACT,
+// NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF OR IN CONNECTION WITH THE USE OR PERFORMANCE
+// OF THIS SOFTWARE.
+
+//! TLS termination, with a certificate per host selected with SNI.
+//!
+//! The certificates are reloaded when their files change or when receiving SIGHUP. Connections
+//! already established keep the certificate they were established with.
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+
+use anyhow::{Context, Result};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::{self, CertifiedKey};
+use rustls::{Certificate, PrivateKey, ServerConfig};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::task;
+use tokio::time::{self, Duration};
+use tokio_rustls::TlsAcceptor;
+
+/// How often the certificate files are checked for modifications.
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone)]
+pub struct TlsFiles {
+    /// Certificate used when there is no certificate specific to the requested host.
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    /// Directory containing "<hostname>.crt" and "<hostname>.key" files.
+    pub cert_dir: Option<PathBuf>,
+}
+
+impl TlsFiles {
+    /// Certificate and key files of each host, with their hostname.
+    fn host_files(&self) -> Result<Vec<(String, PathBuf, PathBuf)>> {
+        let dir = match &self.cert_dir {
+            Some(dir) => dir,
+            None => return Ok(Vec::new()),
+        };
+        let mut files = Vec::new();
+        let entries = fs::read_dir(dir)
+            .with_context(|| format!("Could not read directory {}", dir.display()))?;
+        for entry in entries {
+            let cert = entry?.path();
+            if cert.extension().map(|e| e != "crt").unwrap_or(true) {
+                continue;
+            }
+            let key = cert.with_extension("key");
+            let host = cert.file_stem().and_then(|s| s.to_str());
+            if let (Some(host), true) = (host, key.exists()) {
+                files.push((host.to_lowercase(), cert, key));
+            }
+        }
+        Ok(files)
+    }
+
+    /// Modification times of all the files, to know when they need to be reloaded.
+    fn fingerprint(&self) -> Vec<(PathBuf, Option<SystemTime>)> {
+        let host_files = self.host_files().unwrap_or_default();
+        let paths = [&self.cert, &self.key]
+            .into_iter()
+            .chain(host_files.iter().flat_map(|(_, c, k)| [c, k]));
+        paths
+            .map(|p| {
+                let modified = fs::metadata(p).and_then(|m| m.modified()).ok();
+                (p.clone(), modified)
+            })
+            .collect()
+    }
+}
+
+fn load_certified_key(cert: &Path, key: &Path) -> Result<CertifiedKey> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert)?))
+        .with_context(|| format!("Could not read certificates from {}", cert.display()))?;
+    anyhow::ensure!(!certs.is_empty(), "No certificate in {}", cert.display());
+
+    let mut reader = BufReader::new(File::open(key)?);
+    let key = loop {
+        match rustls_pemfile::read_one(&mut reader)
+            .with_context(|| format!("Could not read private key from {}", key.display()))?
+        {
+            Some(rustls_pemfile::Item::RSAKey(key))
+            | Some(rustls_pemfile::Item::PKCS8Key(key))
+            | Some(rustls_pemfile::Item::ECKey(key)) => break PrivateKey(key),
+            Some(_) => continue,
+            None => anyhow::bail!("No private key in {}", key.display()),
+        }
+    };
+    let key = sign::any_supported_type(&key).context("Unsupported private key")?;
+
+    Ok(CertifiedKey::new(
+        certs.into_iter().map(Certificate).collect(),
+        key,
+    ))
+}
+
+#[derive(Default)]
+struct Certificates {
+    default: Option<Arc<CertifiedKey>>,
+    hosts: HashMap<String, Arc<CertifiedKey>>,
+}
+
+/// Select the certificate from the server name sent by the client.
+pub struct Resolver {
+    files: TlsFiles,
+    certificates: RwLock<Arc<Certificates>>,
+}
+
+impl Resolver {
+    pub fn new(files: TlsFiles) -> Result<Self> {
+        let resolver = Resolver {
+            files,
+            certificates: Default::default(),
+        };
+        resolver.reload()?;
+        Ok(resolver)
+    }
+
+    fn reload(&self) -> Result<()> {
+        let mut certificates = Certificates {
+            default: Some(Arc::new(load_certified_key(
+                &self.files.cert,
+                &self.files.key,
+            )?)),
+            hosts: HashMap::new(),
+        };
+        for (host, cert, key) in self.files.host_files()? {
+            let certified_key = load_certified_key(&cert, &key)
+                .with_context(|| format!("Could not load certificate of {host}"))?;
+            certificates.hosts.insert(host, Arc::new(certified_key));
+        }
+        log::info!(
+            "Loaded TLS certificates for {count} hosts",
+            count = certificates.hosts.len()
+        );
+        *self.certificates.write().expect("TLS lock poisoned") = Arc::new(certificates);
+        Ok(())
+    }
+}
+
+impl ResolvesServerCert for Resolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let certificates = self.certificates.read().expect("TLS lock poisoned").clone();
+        let name = client_hello.server_name().map(str::to_lowercase);
+        let wildcard = name
+            .as_deref()
+            .and_then(|n| n.split_once('.'))
+            .map(|(_, domain)| format!("*.{domain}"));
+        [name, wildcard]
+            .iter()
+            .flatten()
+            .find_map(|n| certificates.hosts.get(n))
+            .or(certificates.default.as_ref())
+            .cloned()
+    }
+}
+
+/// Reload the certificates on SIGHUP, or when the files change.
+pub async fn reload(resolver: Arc<Resolver>) -> Result<()> {
+    let mut sighup = signal(SignalKind::hangup()).context("Could not handle SIGHUP")?;
+    let mut ticker = time::interval(RELOAD_CHECK_INTERVAL);
+    let mut fingerprint = resolver.files.fingerprint();
+    loop {
+        tokio::select! {
+            _ = sighup.recv() => log::info!("Received SIGHUP, reloading TLS certificates"),
+            _ = ticker.tick() => {
+                let new_fingerprint = resolver.files.fingerprint();
+                if new_fingerprint == fingerprint {
+                    continue;
+                }
+                log::info!("TLS certificates changed, reloading them");
+            }
+        }
+        fingerprint = resolver.files.fingerprint();
+        let resolver = resolver.clone();
+        match task::spawn_blocking(move || resolver.reload()).await {
+            Ok(Ok(())) => (),
+            Ok(Err(err)) => log::error!("Could not reload TLS certificates: {err:#}"),
+            Err(err) => log::error!("TLS certificates loader panicked: {err}"),
+        }
+    }
+}
+
+/// Build the TLS acceptor, advertising HTTP/2 and HTTP/1.1 with ALPN.
+pub fn acceptor(resolver: Arc<Resolver>) -> TlsAcceptor {
+    let mut config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_cert_resolver(resolver);
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    TlsAcceptor::from(Arc::new(config))
+}
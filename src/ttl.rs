@@ -0,0 +1,251 @@
+// Copyright 2023, Antoine Catton
+//
+// Permission to use, copy, modify, and/or distribute this software for any purpose with or without
+// fee is hereby granted, provided that the above copyright notice and this permission notice
+// appear in all copies.
+//
+// THE SOFTWARE IS PROVIDED “AS IS” AND THE AUTHOR DISCLAIMS ALL WARRANTIES WITH REGARD TO THIS
+// SOFTWARE INCLUDING ALL IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS. IN NO EVENT SHALL THE
+// AUTHOR BE LIABLE FOR ANY SPECIAL, DIRECT, INDIRECT, OR CONSEQUENTIAL DAMAGES OR ANY DAMAGES
+// WHATSOEVER RESULTING FROM LOSS OF USE, DATA OR PROFITS, WHETHER IN AN ACTION OF CONTRACT,
+// NEGLIGENCE OR OTHER TORTIOUS ACTION, ARISING OUT OF OR IN CONNECTION WITH THE USE OR PERFORMANCE
+// OF THIS SOFTWARE.
+
+//! Expiration of the values, as requested by the headers of a PUT or a PATCH.
+//!
+//! The first header present is used, in this order:
+//!
+//! * "X-Expire-ms", in milliseconds from now (0 to never expire),
+//! * "X-Expire-At", as an RFC 3339 date or in milliseconds since the Unix epoch (like the
+//!   "X-Expires-At" returned on GET),
+//! * "Cache-Control: max-age", in seconds from now,
+//! * "Expires", as an HTTP date.
+//!
+//! The last two are only used when enabled, since they are otherwise meant for the clients of the
+//! value: "Cache-Control" is stored and returned with it, and "Expires: 0" is a common way to
+//! prevent caching.
+//!
+//! Expirations more than 100 years away are refused. With "X-Expire-Mode: sliding", every read
+//! postpones the expiration by the same duration.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use http::header::{self, HeaderMap};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use tokio::time::{Duration, Instant};
+
+/// Expirations further away are refused, they could overflow the computations of dates.
+pub const MAX_EXPIRATION: Duration = Duration::from_secs(100 * 365 * 24 * 60 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Expire {
+    Never,
+    In(Duration),
+}
+
+impl Expire {
+    pub fn deadline(self) -> Option<Instant> {
+        match self {
+            Expire::Never => None,
+            // The durations parsed from the headers are checked already.
+            Expire::In(duration) => Some(Instant::now() + duration.min(MAX_EXPIRATION)),
+        }
+    }
+}
+
+fn header<'a>(headers: &'a HeaderMap, name: &str) -> Result<Option<&'a str>, String> {
+    headers
+        .get(name)
+        .map(|v| v.to_str().map_err(|_| format!("{name} is not ascii")))
+        .transpose()
+}
+
+/// Parse an RFC 3339 date, or a number of milliseconds since the Unix epoch.
+fn parse_timestamp(timestamp: &str) -> Option<SystemTime> {
+    if let Ok(ms) = timestamp.parse::<u64>() {
+        return UNIX_EPOCH.checked_add(Duration::from_millis(ms));
+    }
+    let date = OffsetDateTime::parse(timestamp, &Rfc3339).ok()?;
+    let nanos = u64::try_from(date.unix_timestamp_nanos()).ok()?;
+    UNIX_EPOCH.checked_add(Duration::from_nanos(nanos))
+}
+
+fn max_age(cache_control: &str) -> Option<&str> {
+    cache_control
+        .split(',')
+        .filter_map(|directive| directive.trim().split_once('='))
+        .find(|(name, _)| name.eq_ignore_ascii_case("max-age"))
+        .map(|(_, value)| value.trim_matches('"'))
+}
+
+/// An expiration in a duration from now, which can't be too far away.
+fn within(name: &str, duration: Duration) -> Result<Expire, String> {
+    if duration > MAX_EXPIRATION {
+        return Err(format!("{name} is more than 100 years away"));
+    }
+    Ok(Expire::In(duration))
+}
+
+/// The time left until an absolute expiration.
+fn until(name: &str, time: SystemTime) -> Result<Expire, String> {
+    let remaining = time
+        .duration_since(SystemTime::now())
+        .ok()
+        .filter(|remaining| !remaining.is_zero())
+        .ok_or_else(|| format!("{name} is in the past"))?;
+    within(name, remaining)
+}
+
+/// Whether a sliding expiration is requested, rather than a fixed one (the default).
+pub fn sliding(headers: &HeaderMap) -> Result<bool, String> {
+    match header(headers, "x-expire-mode")? {
+        None => Ok(false),
+        Some(mode) if mode.eq_ignore_ascii_case("fixed") => Ok(false),
+        Some(mode) if mode.eq_ignore_ascii_case("sliding") => Ok(true),
+        Some(mode) => Err(format!("Unknown expiration mode \"{mode}\"")),
+    }
+}
+
+/// The expiration requested by the headers, None if there is none. "Cache-Control: max-age" and
+/// "Expires" are only used when `cache_control` is true.
+pub fn from_headers(headers: &HeaderMap, cache_control: bool) -> Result<Option<Expire>, String> {
+    if let Some(ms) = header(headers, "x-expire-ms")? {
+        let ms: u64 = ms
+            .parse()
+            .map_err(|_| "X-Expire-ms is not a valid number")?;
+        return match ms {
+            0 => Ok(Some(Expire::Never)),
+            ms => within("X-Expire-ms", Duration::from_millis(ms)).map(Some),
+        };
+    }
+    if let Some(timestamp) = header(headers, "x-expire-at")? {
+        let time = parse_timestamp(timestamp).ok_or(
+            "X-Expire-At is neither an RFC 3339 date nor a Unix timestamp in milliseconds",
+        )?;
+        return until("X-Expire-At", time).map(Some);
+    }
+    if !cache_control {
+        return Ok(None);
+    }
+    if let Some(seconds) = header(headers, header::CACHE_CONTROL.as_str())?.and_then(max_age) {
+        return match seconds.parse::<u64>() {
+            Ok(0) => Err("Cache-Control max-age=0 would expire immediately".to_owned()),
+            Ok(seconds) => within("Cache-Control max-age", Duration::from_secs(seconds)).map(Some),
+            Err(_) => Err("Cache-Control max-age is not a valid number".to_owned()),
+        };
+    }
+    if let Some(date) = header(headers, header::EXPIRES.as_str())? {
+        let time = httpdate::parse_http_date(date).map_err(|_| "Expires is not an HTTP date")?;
+        return until("Expires", time).map(Some);
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expire(headers: &[(&'static str, &str)]) -> Result<Option<Expire>, String> {
+        let headers: HeaderMap = headers
+            .iter()
+            .map(|&(name, value)| (name.parse().unwrap(), value.parse().unwrap()))
+            .collect();
+        from_headers(&headers, false)
+    }
+
+    #[test]
+    fn relative() {
+        assert_eq!(expire(&[]), Ok(None));
+        assert_eq!(expire(&[("x-expire-ms", "0")]), Ok(Some(Expire::Never)));
+        assert_eq!(
+            expire(&[("x-expire-ms", "1500")]),
+            Ok(Some(Expire::In(Duration::from_millis(1500))))
+        );
+        assert!(expire(&[("x-expire-ms", "soon")]).is_err());
+    }
+
+    #[test]
+    fn too_far_away() {
+        let max = u64::MAX.to_string();
+        assert!(expire(&[("x-expire-ms", &max)]).is_err());
+        assert!(expire(&[("x-expire-at", &max)]).is_err());
+        assert!(expire(&[("x-expire-at", "9999-12-31T23:59:59Z")]).is_err());
+        let max_age = format!("max-age={max}").parse().unwrap();
+        let headers: HeaderMap = [(header::CACHE_CONTROL, max_age)].into_iter().collect();
+        assert!(from_headers(&headers, true).is_err());
+        let expires = "Fri, 31 Dec 9999 23:59:59 GMT".parse().unwrap();
+        let headers: HeaderMap = [(header::EXPIRES, expires)].into_iter().collect();
+        assert!(from_headers(&headers, true).is_err());
+    }
+
+    #[test]
+    fn absolute() {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let at = (now + Duration::from_secs(60)).as_millis().to_string();
+        match expire(&[("x-expire-at", &at)]) {
+            Ok(Some(Expire::In(duration))) => assert!(duration <= Duration::from_secs(60)),
+            expire => panic!("Unexpected expiration {expire:?}"),
+        }
+        assert!(matches!(
+            expire(&[("x-expire-at", "2099-01-01T00:00:00+02:00")]),
+            Ok(Some(Expire::In(_)))
+        ));
+    }
+
+    #[test]
+    fn cache_control() {
+        let headers: HeaderMap = [(
+            header::CACHE_CONTROL,
+            "public, max-age=300".parse().unwrap(),
+        )]
+        .into_iter()
+        .collect();
+        assert_eq!(from_headers(&headers, false), Ok(None));
+        assert_eq!(
+            from_headers(&headers, true),
+            Ok(Some(Expire::In(Duration::from_secs(300))))
+        );
+        let headers: HeaderMap = [(
+            header::CACHE_CONTROL,
+            "max-age=0, must-revalidate".parse().unwrap(),
+        )]
+        .into_iter()
+        .collect();
+        assert_eq!(from_headers(&headers, false), Ok(None));
+        assert!(from_headers(&headers, true).is_err());
+    }
+
+    #[test]
+    fn in_the_past() {
+        assert!(expire(&[("x-expire-at", "1")]).is_err());
+        assert!(expire(&[("x-expire-at", "2000-01-01T00:00:00Z")]).is_err());
+    }
+
+    #[test]
+    fn expires() {
+        let expires = |value: &str, cache_control| {
+            let headers: HeaderMap = [(header::EXPIRES, value.parse().unwrap())]
+                .into_iter()
+                .collect();
+            from_headers(&headers, cache_control)
+        };
+        let date = httpdate::fmt_http_date(SystemTime::now() + Duration::from_secs(60));
+        match expires(&date, true) {
+            Ok(Some(Expire::In(duration))) => assert!(duration <= Duration::from_secs(60)),
+            expire => panic!("Unexpected expiration {expire:?}"),
+        }
+        assert!(expires("0", true).is_err());
+        assert!(expires("Sat, 01 Jan 2000 00:00:00 GMT", true).is_err());
+        // Only meant for the clients of the value by default.
+        for value in [date.as_str(), "0", "Sat, 01 Jan 2000 00:00:00 GMT"] {
+            assert_eq!(expires(value, false), Ok(None));
+        }
+    }
+
+    #[test]
+    fn deadline_clamped() {
+        let expire = Expire::In(Duration::MAX);
+        assert!(expire.deadline() <= Some(Instant::now() + MAX_EXPIRATION));
+    }
+}